@@ -0,0 +1,100 @@
+// Compares job throughput of the channel and work-stealing backends.
+//
+// Run with `cargo bench --bench throughput`. The results are printed to stderr.
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use hello::{Backend, ThreadPool};
+
+const JOBS: usize = 100_000;
+const RUNS: usize = 5;
+
+fn main() {
+    let threads = thread::available_parallelism().map_or(4, |n| n.get());
+    eprintln!("{JOBS} jobs on {threads} threads, best of {RUNS} runs");
+
+    for backend in [Backend::Channel, Backend::WorkStealing] {
+        let flat = best_of(|| flat(backend, threads));
+        let nested = best_of(|| nested(backend, threads));
+
+        eprintln!(
+            "{backend:?}: submitted from outside {:>10.0} jobs/s, submitted from jobs {:>10.0} jobs/s",
+            JOBS as f64 / flat.as_secs_f64(),
+            JOBS as f64 / nested.as_secs_f64(),
+        );
+    }
+}
+
+fn best_of(run: impl Fn() -> Duration) -> Duration {
+    (0..RUNS).map(|_| run()).min().unwrap()
+}
+
+fn build(backend: Backend, threads: usize) -> ThreadPool {
+    ThreadPool::builder().num_threads(threads).backend(backend).build().unwrap()
+}
+
+// A count of finished jobs, and a channel to tell the main thread once they have all finished
+fn counter() -> (Arc<AtomicUsize>, mpsc::Sender<()>, mpsc::Receiver<()>) {
+    let (done_tx, done_rx) = mpsc::channel();
+    (Arc::new(AtomicUsize::new(0)), done_tx, done_rx)
+}
+
+fn finish(count: &AtomicUsize, total: usize, done: &mpsc::Sender<()>) {
+    if count.fetch_add(1, Ordering::SeqCst) + 1 == total {
+        done.send(()).unwrap();
+    }
+}
+
+// Every job is submitted by the main thread, which is how the server uses the pool
+fn flat(backend: Backend, threads: usize) -> Duration {
+    let pool = build(backend, threads);
+    let (count, done_tx, done_rx) = counter();
+
+    let start = Instant::now();
+    for _ in 0..JOBS {
+        let count = Arc::clone(&count);
+        let done_tx = done_tx.clone();
+        pool.execute(move || finish(&count, JOBS, &done_tx));
+    }
+    done_rx.recv().unwrap();
+
+    start.elapsed()
+}
+
+// One job per thread is submitted from outside, and each of those submits its share of the jobs
+fn nested(backend: Backend, threads: usize) -> Duration {
+    let pool = Arc::new(build(backend, threads));
+    let per_root = JOBS / threads;
+    let total = per_root * threads + threads;
+    let (count, done_tx, done_rx) = counter();
+
+    let start = Instant::now();
+    for _ in 0..threads {
+        let pool_ref = Arc::clone(&pool);
+        let count = Arc::clone(&count);
+        let done_tx = done_tx.clone();
+
+        pool.execute(move || {
+            for _ in 0..per_root {
+                let count = Arc::clone(&count);
+                let done_tx = done_tx.clone();
+                pool_ref.execute(move || finish(&count, total, &done_tx));
+            }
+
+            // The root has to let go of the pool before it counts as finished,
+            // otherwise it could end up dropping the last reference on a worker thread
+            drop(pool_ref);
+            finish(&count, total, &done_tx);
+        });
+    }
+    done_rx.recv().unwrap();
+
+    start.elapsed()
+}
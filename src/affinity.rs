@@ -0,0 +1,113 @@
+use std::io;
+
+use crate::PoolCreationError;
+
+/// How worker threads are pinned to CPU cores, set with `ThreadPoolBuilder::core_affinity`.
+///
+/// Pinning uses `sched_setaffinity` and only has an effect on Linux.
+/// On other platforms the setting is ignored and the OS schedules workers wherever it likes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CoreAffinity {
+    /// Spread the workers over the cores the process is allowed to run on, one core per worker,
+    /// going back to the first core once every core has a worker.
+    RoundRobin,
+    /// Pin the workers to the given cores in turn, going back to the start of the list once it runs out.
+    Cores(Vec<usize>),
+}
+
+// Works out the list of cores the workers are pinned to in turn, worker `id` getting `cores[id % cores.len()]`.
+// Returns None where pinning isn't supported.
+pub(crate) fn resolve(affinity: &CoreAffinity) -> Result<Option<Vec<usize>>, PoolCreationError> {
+    if let CoreAffinity::Cores(cores) = affinity {
+        if cores.is_empty() {
+            return Err(PoolCreationError::EmptyCoreList);
+        }
+    }
+
+    let Some(allowed) = allowed_cores() else {
+        return Ok(None);
+    };
+
+    match affinity {
+        CoreAffinity::RoundRobin => Ok(Some(allowed)),
+        CoreAffinity::Cores(cores) => {
+            // The kernel refuses to pin a thread to a core outside the process's own mask, so catch that up front
+            if let Some(&core) = cores.iter().find(|core| !allowed.contains(core)) {
+                return Err(PoolCreationError::UnavailableCore(core));
+            }
+
+            Ok(Some(cores.clone()))
+        }
+    }
+}
+
+// The cores the calling thread is allowed to run on, or None where we can't tell
+#[cfg(target_os = "linux")]
+fn allowed_cores() -> Option<Vec<usize>> {
+    linux::get_affinity().ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn allowed_cores() -> Option<Vec<usize>> {
+    None
+}
+
+// Pins the calling thread to a single core
+#[cfg(target_os = "linux")]
+pub(crate) fn pin_current_thread(core: usize) -> io::Result<()> {
+    linux::set_affinity(core)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn pin_current_thread(_core: usize) -> io::Result<()> {
+    Ok(())
+}
+
+// The standard library already links against libc, so we can declare the two functions we need ourselves
+// rather than pulling in the libc crate
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{io, mem};
+
+    // glibc's cpu_set_t is a bitmask with room for 1024 CPUs
+    const CPU_SETSIZE: usize = 1024;
+    const BITS: usize = u64::BITS as usize;
+
+    #[repr(C)]
+    struct CpuSet {
+        bits: [u64; CPU_SETSIZE / BITS],
+    }
+
+    extern "C" {
+        fn sched_getaffinity(pid: i32, cpusetsize: usize, mask: *mut CpuSet) -> i32;
+        fn sched_setaffinity(pid: i32, cpusetsize: usize, mask: *const CpuSet) -> i32;
+    }
+
+    // A pid of 0 means the calling thread
+    pub(super) fn get_affinity() -> io::Result<Vec<usize>> {
+        let mut set = CpuSet { bits: [0; CPU_SETSIZE / BITS] };
+
+        // SAFETY: `set` is a valid, writable cpu_set_t of the size we pass in
+        if unsafe { sched_getaffinity(0, mem::size_of::<CpuSet>(), &mut set) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok((0..CPU_SETSIZE).filter(|&cpu| set.bits[cpu / BITS] & (1 << (cpu % BITS)) != 0).collect())
+    }
+
+    pub(super) fn set_affinity(core: usize) -> io::Result<()> {
+        if core >= CPU_SETSIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "core number is too large"));
+        }
+
+        let mut set = CpuSet { bits: [0; CPU_SETSIZE / BITS] };
+        set.bits[core / BITS] |= 1 << (core % BITS);
+
+        // SAFETY: `set` is a valid cpu_set_t of the size we pass in, and the kernel only reads it
+        if unsafe { sched_setaffinity(0, mem::size_of::<CpuSet>(), &set) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,261 @@
+use std::{any::Any, fmt, sync::Arc, thread, time::Duration};
+
+use crate::{Backend, CoreAffinity, OverflowPolicy, PanicHandler, PoolCreationError, StuckJob, StuckJobHandler, ThreadHook, ThreadNamer, ThreadPool};
+
+/// Configures and creates a [`ThreadPool`].
+#[derive(Clone)]
+pub struct ThreadPoolBuilder {
+    pub(crate) min_threads: usize,
+    pub(crate) max_threads: usize,
+    pub(crate) keep_alive: Duration,
+    pub(crate) backend: Backend,
+    pub(crate) priority_aging: Duration,
+    pub(crate) queue_capacity: Option<usize>,
+    pub(crate) overflow_policy: OverflowPolicy,
+    pub(crate) panic_handler: Option<Arc<PanicHandler>>,
+    pub(crate) thread_name: Option<Arc<ThreadNamer>>,
+    pub(crate) stack_size: Option<usize>,
+    pub(crate) on_thread_start: Option<Arc<ThreadHook>>,
+    pub(crate) on_thread_stop: Option<Arc<ThreadHook>>,
+    pub(crate) core_affinity: Option<CoreAffinity>,
+    pub(crate) job_budget: Option<Duration>,
+    pub(crate) stuck_job_handler: Option<Arc<StuckJobHandler>>,
+    pub(crate) replace_stuck_workers: bool,
+}
+
+impl ThreadPoolBuilder {
+    /// Create a builder with the default settings: a fixed thread per available CPU and an unbounded queue.
+    ///
+    /// The CPU count comes from [`std::thread::available_parallelism`], which on Linux
+    /// takes the process's affinity mask and cgroup CPU quota into account.
+    pub fn new() -> ThreadPoolBuilder {
+        let num_threads = thread::available_parallelism().map_or(1, |n| n.get());
+
+        ThreadPoolBuilder {
+            min_threads: num_threads,
+            max_threads: num_threads,
+            keep_alive: Duration::from_secs(60),
+            backend: Backend::default(),
+            priority_aging: Duration::from_millis(10),
+            queue_capacity: None,
+            overflow_policy: OverflowPolicy::default(),
+            panic_handler: None,
+            thread_name: None,
+            stack_size: None,
+            on_thread_start: None,
+            on_thread_stop: None,
+            core_affinity: None,
+            job_budget: None,
+            stuck_job_handler: None,
+            replace_stuck_workers: false,
+        }
+    }
+
+    /// Set a fixed number of worker threads in the pool.
+    ///
+    /// This is the same as setting both [`min_threads`](ThreadPoolBuilder::min_threads)
+    /// and [`max_threads`](ThreadPoolBuilder::max_threads) to `num_threads`.
+    pub fn num_threads(mut self, num_threads: usize) -> ThreadPoolBuilder {
+        self.min_threads = num_threads;
+        self.max_threads = num_threads;
+        self
+    }
+
+    /// Set the number of worker threads that are started up front and always kept running.
+    ///
+    /// This can be zero, in which case threads are only started once jobs arrive.
+    pub fn min_threads(mut self, min_threads: usize) -> ThreadPoolBuilder {
+        self.min_threads = min_threads;
+        self
+    }
+
+    /// Set the most worker threads the pool will run at once.
+    ///
+    /// When jobs are queued faster than the running workers can take them, extra workers are
+    /// started up to this limit.
+    pub fn max_threads(mut self, max_threads: usize) -> ThreadPoolBuilder {
+        self.max_threads = max_threads;
+        self
+    }
+
+    /// Set how long a worker above [`min_threads`](ThreadPoolBuilder::min_threads) waits
+    /// for a job before it exits. Defaults to 60 seconds.
+    pub fn keep_alive(mut self, keep_alive: Duration) -> ThreadPoolBuilder {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// Set how jobs are handed to the workers. Defaults to [`Backend::Channel`].
+    pub fn backend(mut self, backend: Backend) -> ThreadPoolBuilder {
+        self.backend = backend;
+        self
+    }
+
+    /// Set how long a queued job has to wait to be treated as one point higher [`Priority`](crate::Priority).
+    ///
+    /// This stops a steady stream of high priority jobs from starving low priority ones forever.
+    /// Defaults to 10 milliseconds, so a [`Priority::LOW`](crate::Priority::LOW) job overtakes new
+    /// [`Priority::NORMAL`](crate::Priority::NORMAL) jobs
+    /// once it has been waiting for 640 milliseconds.
+    pub fn priority_aging(mut self, interval: Duration) -> ThreadPoolBuilder {
+        self.priority_aging = interval;
+        self
+    }
+
+    /// Limit the number of jobs that can be waiting in the queue.
+    ///
+    /// What happens when the queue is full is decided by the [`OverflowPolicy`]. The jobs the pool queues itself to
+    /// carry on work it has already accepted, such as the next job for a key passed to `ThreadPool::execute_keyed`,
+    /// the dependents of a finished `TaskGraph` node or the next poll of a woken future, can go past the capacity,
+    /// as waiting for room on a worker could deadlock the pool.
+    pub fn queue_capacity(mut self, capacity: usize) -> ThreadPoolBuilder {
+        self.queue_capacity = Some(capacity);
+        self
+    }
+
+    /// Set what happens to new jobs when the queue is full. Defaults to [`OverflowPolicy::Block`].
+    ///
+    /// This has no effect unless a [`queue_capacity`](ThreadPoolBuilder::queue_capacity) is set.
+    pub fn overflow_policy(mut self, policy: OverflowPolicy) -> ThreadPoolBuilder {
+        self.overflow_policy = policy;
+        self
+    }
+
+    /// Set a function to be called whenever a job panics.
+    ///
+    /// The handler receives the id of the worker that ran the job and the panic payload.
+    /// The worker carries on with the next job either way.
+    pub fn panic_handler<H>(mut self, handler: H) -> ThreadPoolBuilder
+    where
+        H: Fn(usize, Box<dyn Any + Send>) + Send + Sync + 'static,
+    {
+        self.panic_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Name the worker threads `{prefix}-{id}`, where `id` is the worker's id.
+    ///
+    /// The name shows up in panic messages, debuggers and tools like `top -H`.
+    /// Linux only shows the first 15 bytes, so keep the prefix short.
+    pub fn thread_name(self, prefix: impl Into<String>) -> ThreadPoolBuilder {
+        let prefix = prefix.into();
+        self.thread_name_fn(move |id| format!("{prefix}-{id}"))
+    }
+
+    /// Name the worker threads with a function that is given each worker's id.
+    pub fn thread_name_fn<N>(mut self, namer: N) -> ThreadPoolBuilder
+    where
+        N: Fn(usize) -> String + Send + Sync + 'static,
+    {
+        self.thread_name = Some(Arc::new(namer));
+        self
+    }
+
+    /// Set the stack size, in bytes, of the worker threads.
+    ///
+    /// Defaults to the standard library's default, see [`std::thread::Builder::stack_size`].
+    pub fn stack_size(mut self, bytes: usize) -> ThreadPoolBuilder {
+        self.stack_size = Some(bytes);
+        self
+    }
+
+    /// Set a function to run on each worker thread as it starts, before it picks up any jobs.
+    ///
+    /// The function is given the worker's id. This is the place for thread-local setup, such as loggers.
+    /// A worker whose hook panics exits without being replaced, and [`build`](ThreadPoolBuilder::build) returns
+    /// [`PoolCreationError::WorkerStartPanicked`] if that happens to one of the workers started up front.
+    pub fn on_thread_start<H>(mut self, hook: H) -> ThreadPoolBuilder
+    where
+        H: Fn(usize) + Send + Sync + 'static,
+    {
+        self.on_thread_start = Some(Arc::new(hook));
+        self
+    }
+
+    /// Set a function to run on each worker thread as it exits, whether it is shutting down, retiring or dying.
+    ///
+    /// The function is given the worker's id. It can run while the thread is unwinding from a panic, so it must not panic itself.
+    pub fn on_thread_stop<H>(mut self, hook: H) -> ThreadPoolBuilder
+    where
+        H: Fn(usize) + Send + Sync + 'static,
+    {
+        self.on_thread_stop = Some(Arc::new(hook));
+        self
+    }
+
+    /// Pin each worker thread to a CPU core. By default workers aren't pinned.
+    ///
+    /// Cores are handed out by worker id, so a worker started to replace one that died or retired
+    /// may land on a different core than the one it replaces. See [`ThreadPool::worker_cores`].
+    pub fn core_affinity(mut self, affinity: CoreAffinity) -> ThreadPoolBuilder {
+        self.core_affinity = Some(affinity);
+        self
+    }
+
+    /// Set how long any job may run before the watchdog reports it as stuck. By default there is no limit.
+    ///
+    /// Setting a budget starts a watchdog thread that checks on the running jobs ten times a second.
+    /// Stuck jobs are reported to the [`on_stuck_job`](ThreadPoolBuilder::on_stuck_job) handler, or logged as
+    /// a warning if there isn't one. Individual jobs can be given their own budget with `ThreadPool::execute_with_budget`.
+    pub fn job_budget(mut self, budget: Duration) -> ThreadPoolBuilder {
+        self.job_budget = Some(budget);
+        self
+    }
+
+    /// Set a function to be called whenever the watchdog finds a job that has gone over its time budget.
+    ///
+    /// Each job is reported once, with the id of its worker, its label and how long it has been running.
+    /// The handler runs on the watchdog thread, so it should return quickly.
+    pub fn on_stuck_job<H>(mut self, handler: H) -> ThreadPoolBuilder
+    where
+        H: Fn(&StuckJob) + Send + Sync + 'static,
+    {
+        self.stuck_job_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Start a replacement worker whenever a job goes over its time budget, so the stuck job doesn't cost
+    /// the pool a worker. Defaults to `false`.
+    ///
+    /// The stuck worker stops counting towards the pool's size and exits once its job finishes, if it ever does.
+    /// Shutting down the pool doesn't wait for it.
+    pub fn replace_stuck_workers(mut self, replace: bool) -> ThreadPoolBuilder {
+        self.replace_stuck_workers = replace;
+        self
+    }
+
+    /// Create the pool and start its worker threads.
+    pub fn build(self) -> Result<ThreadPool, PoolCreationError> {
+        ThreadPool::start(self)
+    }
+}
+
+// The handlers and hooks are closures, so we can't derive Debug
+impl fmt::Debug for ThreadPoolBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ThreadPoolBuilder")
+            .field("min_threads", &self.min_threads)
+            .field("max_threads", &self.max_threads)
+            .field("keep_alive", &self.keep_alive)
+            .field("backend", &self.backend)
+            .field("priority_aging", &self.priority_aging)
+            .field("queue_capacity", &self.queue_capacity)
+            .field("overflow_policy", &self.overflow_policy)
+            .field("panic_handler", &self.panic_handler.is_some())
+            .field("thread_name", &self.thread_name.is_some())
+            .field("stack_size", &self.stack_size)
+            .field("on_thread_start", &self.on_thread_start.is_some())
+            .field("on_thread_stop", &self.on_thread_stop.is_some())
+            .field("core_affinity", &self.core_affinity)
+            .field("job_budget", &self.job_budget)
+            .field("stuck_job_handler", &self.stuck_job_handler.is_some())
+            .field("replace_stuck_workers", &self.replace_stuck_workers)
+            .finish()
+    }
+}
+
+impl Default for ThreadPoolBuilder {
+    fn default() -> ThreadPoolBuilder {
+        ThreadPoolBuilder::new()
+    }
+}
@@ -0,0 +1,161 @@
+use std::{
+    fmt, mem,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex, PoisonError, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{lock, queue::Runnable, Shared};
+
+/// A flag that asks jobs to stop, handed to jobs started with `ThreadPool::execute_cancellable`.
+///
+/// Cancellation is cooperative: a job that is already running carries on until it checks
+/// [`is_cancelled`](CancellationToken::is_cancelled), while jobs that haven't started yet are
+/// taken off the queue and never run. Clones share the same flag.
+#[derive(Clone)]
+pub struct CancellationToken {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    cancelled: AtomicBool,
+    state: Mutex<State>,
+    // Signalled when the token is cancelled, so sleep_or_cancel can wake up early
+    changed: Condvar,
+}
+
+struct State {
+    // Tokens made with child_token, cancelled along with this one
+    children: Vec<Weak<Inner>>,
+    // Pools with jobs tied to this token, which get their queues cleared of those jobs on cancel
+    pools: Vec<Weak<Shared>>,
+}
+
+impl CancellationToken {
+    /// Create a token that hasn't been cancelled.
+    pub fn new() -> CancellationToken {
+        CancellationToken {
+            inner: Arc::new(Inner {
+                cancelled: AtomicBool::new(false),
+                state: Mutex::new(State {
+                    children: Vec::new(),
+                    pools: Vec::new(),
+                }),
+                changed: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Create a token that is cancelled whenever this one is, but can also be cancelled on its own.
+    pub fn child_token(&self) -> CancellationToken {
+        let child = CancellationToken::new();
+        let mut state = lock(&self.inner.state);
+
+        // Checked under the lock, as cancel() sets the flag before taking the lock to collect the children
+        if self.is_cancelled() {
+            child.inner.cancelled.store(true, Ordering::SeqCst);
+        } else {
+            state.children.retain(|child| child.strong_count() > 0);
+            state.children.push(Arc::downgrade(&child.inner));
+        }
+
+        child
+    }
+
+    /// Cancel the token and all of its children.
+    ///
+    /// Jobs tied to the token that are still queued are removed from the queue and dropped without running.
+    pub fn cancel(&self) {
+        if self.inner.cancelled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let mut state = lock(&self.inner.state);
+        let children = mem::take(&mut state.children);
+        let pools = mem::take(&mut state.pools);
+        self.inner.changed.notify_all();
+        drop(state);
+
+        for child in children.iter().filter_map(Weak::upgrade) {
+            CancellationToken { inner: child }.cancel();
+        }
+
+        for shared in pools.iter().filter_map(Weak::upgrade) {
+            let removed = shared.queue.remove_cancelled();
+            shared.metrics.jobs_dropped(removed);
+        }
+    }
+
+    /// Returns `true` if the token has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Sleep for `duration`, waking up early if the token is cancelled.
+    ///
+    /// Returns `true` if the token was cancelled, or `false` if the whole duration passed.
+    pub fn sleep_or_cancel(&self, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+        let mut state = lock(&self.inner.state);
+
+        loop {
+            if self.is_cancelled() {
+                return true;
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+
+            state = self
+                .inner
+                .changed
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+
+    // Remembers that the pool has jobs tied to this token. Returns false if the token is already cancelled.
+    pub(crate) fn register_pool(&self, shared: &Arc<Shared>) -> bool {
+        let mut state = lock(&self.inner.state);
+
+        if self.is_cancelled() {
+            return false;
+        }
+
+        if !state.pools.iter().any(|pool| pool.as_ptr() == Arc::as_ptr(shared)) {
+            state.pools.retain(|pool| pool.strong_count() > 0);
+            state.pools.push(Arc::downgrade(shared));
+        }
+
+        true
+    }
+}
+
+// A job started with `ThreadPool::execute_with_token`, which hands the token to the caller's closure when it runs
+pub(crate) struct TokenJob<F> {
+    pub(crate) f: F,
+    pub(crate) token: CancellationToken,
+}
+
+impl<F: FnOnce(&CancellationToken) + Send + 'static> Runnable for TokenJob<F> {
+    fn run(self) {
+        (self.f)(&self.token)
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> CancellationToken {
+        CancellationToken::new()
+    }
+}
+
+impl fmt::Debug for CancellationToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CancellationToken").field("cancelled", &self.is_cancelled()).finish()
+    }
+}
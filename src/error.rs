@@ -0,0 +1,251 @@
+use std::{any::Any, error::Error, fmt, io};
+
+/// The error returned by `ThreadPool::build` when the pool could not be started.
+#[derive(Debug)]
+pub enum PoolCreationError {
+    /// The pool was asked to run with zero threads.
+    ZeroSize,
+    /// The pool was configured with a minimum number of threads above its maximum.
+    InvalidThreadRange { min: usize, max: usize },
+    /// The pool was given a job queue that can't hold any jobs.
+    ZeroQueueCapacity,
+    /// The pool was told to pin its workers to an empty list of cores.
+    EmptyCoreList,
+    /// The pool was told to pin a worker to a core the process isn't allowed to run on.
+    UnavailableCore(usize),
+    /// The operating system refused to spawn one of the worker threads.
+    ///
+    /// `started` is the number of workers that were already running when the spawn failed.
+    /// Those workers have been shut down and joined before this error is returned.
+    SpawnFailed {
+        requested: usize,
+        started: usize,
+        source: io::Error,
+    },
+    /// The operating system refused to spawn the watchdog thread needed for `ThreadPoolBuilder::job_budget`.
+    WatchdogFailed(io::Error),
+    /// A worker thread panicked before it could take any jobs, in its `ThreadPoolBuilder::on_thread_start` hook.
+    ///
+    /// The workers that did start have been shut down and joined before this error is returned.
+    WorkerStartPanicked,
+}
+
+impl fmt::Display for PoolCreationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolCreationError::ZeroSize => write!(f, "thread pool size must be greater than zero"),
+            PoolCreationError::InvalidThreadRange { min, max } => write!(
+                f,
+                "thread pool minimum size ({min}) is greater than its maximum size ({max})"
+            ),
+            PoolCreationError::ZeroQueueCapacity => {
+                write!(f, "thread pool queue capacity must be greater than zero")
+            }
+            PoolCreationError::EmptyCoreList => write!(f, "thread pool core list must not be empty"),
+            PoolCreationError::UnavailableCore(core) => {
+                write!(f, "core {core} is not in the set of cores this process may run on")
+            }
+            PoolCreationError::SpawnFailed { requested, started, source } => write!(
+                f,
+                "failed to spawn worker thread ({started} of {requested} started): {source}"
+            ),
+            PoolCreationError::WatchdogFailed(source) => write!(f, "failed to spawn watchdog thread: {source}"),
+            PoolCreationError::WorkerStartPanicked => f.write_str("a worker thread panicked in its start hook"),
+        }
+    }
+}
+
+impl Error for PoolCreationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PoolCreationError::ZeroSize
+            | PoolCreationError::InvalidThreadRange { .. }
+            | PoolCreationError::ZeroQueueCapacity
+            | PoolCreationError::EmptyCoreList
+            | PoolCreationError::UnavailableCore(_)
+            | PoolCreationError::WorkerStartPanicked => None,
+            PoolCreationError::SpawnFailed { source, .. } | PoolCreationError::WatchdogFailed(source) => Some(source),
+        }
+    }
+}
+
+/// The error returned by `ThreadPool::try_execute` and the other `try_` functions when a job could not be submitted.
+///
+/// The rejected closure is handed back so the caller can run it elsewhere or drop it.
+pub enum ExecuteError<F> {
+    /// The pool has started shutting down and no longer accepts jobs.
+    ShuttingDown(F),
+    /// Every worker thread in the pool has exited, so the job would never run.
+    NoWorkers(F),
+    /// The job queue is full and the pool's overflow policy is `OverflowPolicy::Reject`.
+    QueueFull(F),
+    /// The job was given a time budget, but the watchdog thread that enforces it could not be spawned.
+    WatchdogFailed(F, io::Error),
+}
+
+impl<F> ExecuteError<F> {
+    /// Take back the closure that was rejected.
+    pub fn into_inner(self) -> F {
+        match self {
+            ExecuteError::ShuttingDown(f)
+            | ExecuteError::NoWorkers(f)
+            | ExecuteError::QueueFull(f)
+            | ExecuteError::WatchdogFailed(f, _) => f,
+        }
+    }
+
+    // Swaps the rejected job for something else, such as the caller's closure from inside a job the pool wrapped
+    pub(crate) fn map<G>(self, op: impl FnOnce(F) -> G) -> ExecuteError<G> {
+        match self {
+            ExecuteError::ShuttingDown(f) => ExecuteError::ShuttingDown(op(f)),
+            ExecuteError::NoWorkers(f) => ExecuteError::NoWorkers(op(f)),
+            ExecuteError::QueueFull(f) => ExecuteError::QueueFull(op(f)),
+            ExecuteError::WatchdogFailed(f, e) => ExecuteError::WatchdogFailed(op(f), e),
+        }
+    }
+}
+
+// Closures don't implement Debug, so we can't derive it
+impl<F> fmt::Debug for ExecuteError<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecuteError::ShuttingDown(_) => f.write_str("ShuttingDown(..)"),
+            ExecuteError::NoWorkers(_) => f.write_str("NoWorkers(..)"),
+            ExecuteError::QueueFull(_) => f.write_str("QueueFull(..)"),
+            ExecuteError::WatchdogFailed(_, e) => write!(f, "WatchdogFailed(.., {e:?})"),
+        }
+    }
+}
+
+impl<F> fmt::Display for ExecuteError<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecuteError::ShuttingDown(_) => f.write_str("thread pool is shutting down"),
+            ExecuteError::NoWorkers(_) => f.write_str("thread pool has no live workers"),
+            ExecuteError::QueueFull(_) => f.write_str("thread pool queue is full"),
+            ExecuteError::WatchdogFailed(_, e) => write!(f, "failed to start watchdog thread: {e}"),
+        }
+    }
+}
+
+impl<F> Error for ExecuteError<F> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ExecuteError::WatchdogFailed(_, e) => Some(e),
+            ExecuteError::ShuttingDown(_) | ExecuteError::NoWorkers(_) | ExecuteError::QueueFull(_) => None,
+        }
+    }
+}
+
+/// The error returned when waiting on a `JobHandle` for a job that did not produce a value.
+#[derive(Debug)]
+pub enum JoinError {
+    /// The job panicked. This holds the value the job panicked with.
+    Panicked(Box<dyn Any + Send + 'static>),
+    /// The job was dropped without running, for example because the pool rejected it.
+    Cancelled,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::Panicked(payload) => write_panic(f, &**payload),
+            JoinError::Cancelled => f.write_str("job was cancelled before it ran"),
+        }
+    }
+}
+
+impl Error for JoinError {}
+
+/// The error returned by `JobGroup::wait` when the group's jobs did not all succeed.
+///
+/// It is also what a failed node of a `TaskGraph` is reported with, in a [`NodeFailure`].
+#[derive(Debug)]
+pub enum GroupError {
+    /// A job returned an error. This holds the first error returned by any job in the group.
+    Failed(Box<dyn Error + Send + Sync + 'static>),
+    /// A job panicked. This holds the value the first job to panic panicked with.
+    Panicked(Box<dyn Any + Send + 'static>),
+    /// The group was cancelled before all of its jobs ran.
+    Cancelled,
+    /// Some of the group's jobs were dropped by the pool without running, either to make room for newer jobs
+    /// under `OverflowPolicy::DropOldest` or because the pool shut down before getting to them.
+    Dropped,
+}
+
+impl fmt::Display for GroupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GroupError::Failed(e) => write!(f, "job failed: {e}"),
+            GroupError::Panicked(payload) => write_panic(f, &**payload),
+            GroupError::Cancelled => f.write_str("job group was cancelled"),
+            GroupError::Dropped => f.write_str("jobs in the group were dropped without running"),
+        }
+    }
+}
+
+impl Error for GroupError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            GroupError::Failed(e) => Some(&**e),
+            GroupError::Panicked(_) | GroupError::Cancelled | GroupError::Dropped => None,
+        }
+    }
+}
+
+/// The error returned by `TaskGraph::wait` when some of the graph's nodes did not run to success.
+#[derive(Debug)]
+pub struct GraphError {
+    /// The nodes that returned an error or panicked, in the order they finished.
+    pub failed: Vec<NodeFailure>,
+    /// The names of the nodes that never ran, because a node they depend on failed or the pool rejected them.
+    pub cancelled: Vec<String>,
+}
+
+/// A node of a `TaskGraph` that returned an error or panicked.
+#[derive(Debug)]
+pub struct NodeFailure {
+    /// The name the node was added with.
+    pub name: String,
+    /// What went wrong, either [`GroupError::Failed`] or [`GroupError::Panicked`].
+    pub error: GroupError,
+}
+
+impl fmt::Display for GraphError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(first) = self.failed.first() {
+            write!(f, "{} failed: {}", first.name, first.error)?;
+
+            if self.failed.len() > 1 {
+                write!(f, " (and {} more)", self.failed.len() - 1)?;
+            }
+
+            if !self.cancelled.is_empty() {
+                f.write_str("; ")?;
+            }
+        }
+
+        if !self.cancelled.is_empty() {
+            write!(f, "cancelled: {}", self.cancelled.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Error for GraphError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.failed.first().map(|failure| &failure.error as &(dyn Error + 'static))
+    }
+}
+
+fn write_panic(f: &mut fmt::Formatter<'_>, payload: &(dyn Any + Send)) -> fmt::Result {
+    // panic!() with a literal gives a &str, with format arguments it gives a String
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        write!(f, "job panicked: {message}")
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        write!(f, "job panicked: {message}")
+    } else {
+        f.write_str("job panicked")
+    }
+}
@@ -0,0 +1,388 @@
+use std::{
+    convert::Infallible,
+    error::Error,
+    fmt, mem,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, PoisonError, Weak,
+    },
+};
+
+use crate::{
+    group::{run_fallible, Record, Tracker},
+    lock,
+    queue::JobInfo,
+    GraphError, GroupError, NodeFailure, Priority, Shared,
+};
+
+// Gives every graph its own id so a node can't be used as a dependency in a graph it doesn't belong to
+static NEXT_GRAPH_ID: AtomicUsize = AtomicUsize::new(0);
+
+// A node's job, already wrapped to catch panics and turn errors into a GroupError
+type NodeJob = Box<dyn FnOnce() -> Result<(), GroupError> + Send + 'static>;
+
+/// A set of jobs that depend on each other, created by `ThreadPool::graph`.
+///
+/// Each job is a node that names the nodes it depends on when it is added. A node is submitted to the pool as
+/// soon as all of its dependencies have succeeded, so independent branches run in parallel. If a node fails or
+/// panics, every node that depends on it, directly or not, is cancelled without running.
+/// Dependencies have to be added before the nodes that use them, so the graph can't have cycles.
+///
+/// Dropping the graph doesn't cancel or wait for its nodes, they still run as their dependencies finish.
+pub struct TaskGraph {
+    shared: Arc<Shared>,
+    state: Arc<GraphState>,
+}
+
+/// Identifies a node in a [`TaskGraph`], for later nodes to depend on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeId {
+    graph: usize,
+    index: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    // Waiting for dependencies to finish
+    Waiting,
+    // Submitted to the pool, and queued or running
+    Dispatched,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+struct Node {
+    name: Arc<str>,
+    status: Status,
+    // Dependencies that haven't succeeded yet. The node is dispatched when this gets to zero.
+    unfinished: usize,
+    // Nodes that have this one as a dependency
+    dependents: Vec<usize>,
+    // Taken when the node is dispatched or cancelled
+    job: Option<NodeJob>,
+}
+
+struct Progress {
+    nodes: Vec<Node>,
+    // Nodes that are waiting or dispatched
+    pending: usize,
+    // What went wrong since the last call to wait(), in the order it happened
+    failed: Vec<NodeFailure>,
+    cancelled: Vec<String>,
+}
+
+struct GraphState {
+    id: usize,
+    progress: Mutex<Progress>,
+    // Signalled when the last pending node finishes
+    done: Condvar,
+}
+
+// A node that is ready to be submitted to the pool
+struct Ready {
+    name: Arc<str>,
+    job: NodeJob,
+    tracker: Tracker<NodeRecorder>,
+}
+
+// Records how a dispatched node ended, see `GraphState::record`
+struct NodeRecorder {
+    state: Arc<GraphState>,
+    index: usize,
+}
+
+impl Record for NodeRecorder {
+    type Output = Vec<Ready>;
+
+    fn record(&self, result: Option<Result<(), GroupError>>) -> Vec<Ready> {
+        self.state.record(self.index, result)
+    }
+}
+
+impl GraphState {
+    // Records how a dispatched node ended, with None meaning it was dropped without running.
+    // Returns the dependents that are now ready to run.
+    fn record(self: &Arc<Self>, index: usize, result: Option<Result<(), GroupError>>) -> Vec<Ready> {
+        let mut progress = lock(&self.progress);
+        let mut ready = Vec::new();
+        let mut dropped = Vec::new();
+
+        progress.pending -= 1;
+        let name = Arc::clone(&progress.nodes[index].name);
+
+        match result {
+            Some(Ok(())) => {
+                progress.nodes[index].status = Status::Succeeded;
+
+                for dependent in mem::take(&mut progress.nodes[index].dependents) {
+                    let node = &mut progress.nodes[dependent];
+
+                    // The dependent may already have been cancelled by another of its dependencies failing
+                    if node.status != Status::Waiting {
+                        continue;
+                    }
+
+                    node.unfinished -= 1;
+                    if node.unfinished == 0 {
+                        ready.push(self.dispatched(node, dependent));
+                    }
+                }
+            }
+            Some(Err(error)) => {
+                progress.nodes[index].status = Status::Failed;
+                progress.failed.push(NodeFailure { name: name.to_string(), error });
+                dropped = cancel_dependents(&mut progress, index);
+            }
+            None => {
+                progress.nodes[index].status = Status::Cancelled;
+                progress.cancelled.push(name.to_string());
+                dropped = cancel_dependents(&mut progress, index);
+            }
+        }
+
+        if progress.pending == 0 {
+            self.done.notify_all();
+        }
+
+        // The cancelled nodes' jobs are only dropped once the lock is released
+        drop(progress);
+        drop(dropped);
+
+        ready
+    }
+
+    // Marks a node whose dependencies have all succeeded as dispatched, and takes its job to submit
+    fn dispatched(self: &Arc<Self>, node: &mut Node, index: usize) -> Ready {
+        node.status = Status::Dispatched;
+
+        Ready {
+            name: Arc::clone(&node.name),
+            job: node.job.take().expect("a waiting node still has its job"),
+            tracker: Tracker::new(NodeRecorder { state: Arc::clone(self), index }),
+        }
+    }
+}
+
+// Cancels everything downstream of a node that failed or was cancelled, returning their jobs to be dropped
+fn cancel_dependents(progress: &mut Progress, index: usize) -> Vec<NodeJob> {
+    let mut dropped = Vec::new();
+    let mut stack = mem::take(&mut progress.nodes[index].dependents);
+
+    while let Some(dependent) = stack.pop() {
+        let node = &mut progress.nodes[dependent];
+
+        // A node can depend on the same node through several paths, but is only cancelled once
+        if node.status != Status::Waiting {
+            continue;
+        }
+
+        node.status = Status::Cancelled;
+        dropped.extend(node.job.take());
+        stack.append(&mut node.dependents);
+
+        let name = node.name.to_string();
+        progress.cancelled.push(name);
+        progress.pending -= 1;
+    }
+
+    dropped
+}
+
+// Submits the nodes that are ready. A node's closure only holds a Weak to the pool, so queued nodes don't keep it alive.
+// `from_pool` is set when a finished node dispatches its dependents from a worker, see `Shared::requeue`.
+fn dispatch(shared: &Arc<Shared>, ready: Vec<Ready>, from_pool: bool) {
+    for Ready { name, job, tracker } in ready {
+        let pool = Arc::downgrade(shared);
+        let node = move || {
+            let ready = tracker.finish(job());
+
+            // If the pool is already gone the dependents are dropped, which counts them as cancelled
+            if let Some(shared) = Weak::upgrade(&pool) {
+                dispatch(&shared, ready, true);
+            }
+        };
+
+        // If the pool rejects the node the tracker is dropped with it, which cancels the node and its dependents
+        let info = JobInfo { label: Some(name), ..JobInfo::default() };
+        let _ = if from_pool {
+            shared.requeue(info, node)
+        } else {
+            shared.try_execute_with(Priority::NORMAL, info, node)
+        };
+    }
+}
+
+impl TaskGraph {
+    pub(crate) fn new(shared: Arc<Shared>) -> TaskGraph {
+        TaskGraph {
+            shared,
+            state: Arc::new(GraphState {
+                id: NEXT_GRAPH_ID.fetch_add(1, Ordering::Relaxed),
+                progress: Mutex::new(Progress {
+                    nodes: Vec::new(),
+                    pending: 0,
+                    failed: Vec::new(),
+                    cancelled: Vec::new(),
+                }),
+                done: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Add a job that runs once every node in `dependencies` has succeeded, or straight away if there are none.
+    ///
+    /// The name identifies the node in a [`GraphError`] and to the watchdog.
+    ///
+    /// # Panics
+    ///
+    /// The `add` function will panic if one of the dependencies belongs to a different graph.
+    pub fn add<F>(&self, name: &str, dependencies: &[NodeId], f: F) -> NodeId
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.add_fallible(name, dependencies, move || {
+            f();
+            Ok::<(), Infallible>(())
+        })
+    }
+
+    /// Add a job that can fail. If it returns an error, every node that depends on it is cancelled.
+    ///
+    /// If a dependency has already failed or been cancelled, the node is cancelled straight away.
+    ///
+    /// # Panics
+    ///
+    /// The `add_fallible` function will panic if one of the dependencies belongs to a different graph.
+    pub fn add_fallible<F, E>(&self, name: &str, dependencies: &[NodeId], f: F) -> NodeId
+    where
+        F: FnOnce() -> Result<(), E> + Send + 'static,
+        E: Into<Box<dyn Error + Send + Sync + 'static>>,
+    {
+        let job: NodeJob = Box::new(move || run_fallible(f));
+
+        let mut progress = lock(&self.state.progress);
+        let index = progress.nodes.len();
+        let mut unfinished = 0;
+        let mut blocked = false;
+
+        for dependency in dependencies {
+            assert!(dependency.graph == self.state.id, "dependency {dependency:?} belongs to a different task graph");
+
+            match progress.nodes[dependency.index].status {
+                Status::Succeeded => {}
+                Status::Failed | Status::Cancelled => blocked = true,
+                Status::Waiting | Status::Dispatched => unfinished += 1,
+            }
+        }
+
+        let mut node = Node {
+            name: name.into(),
+            status: Status::Waiting,
+            unfinished,
+            dependents: Vec::new(),
+            job: None,
+        };
+
+        let mut ready = None;
+
+        if blocked {
+            node.status = Status::Cancelled;
+            progress.cancelled.push(name.to_owned());
+        } else {
+            node.job = Some(job);
+            progress.pending += 1;
+
+            for dependency in dependencies {
+                let dependency = &mut progress.nodes[dependency.index];
+                if matches!(dependency.status, Status::Waiting | Status::Dispatched) {
+                    dependency.dependents.push(index);
+                }
+            }
+
+            if unfinished == 0 {
+                ready = Some(self.state.dispatched(&mut node, index));
+            }
+        }
+
+        progress.nodes.push(node);
+        drop(progress);
+
+        dispatch(&self.shared, ready.into_iter().collect(), false);
+
+        NodeId { graph: self.state.id, index }
+    }
+
+    /// Block until every node has either run or been cancelled.
+    ///
+    /// Returns a [`GraphError`] listing the nodes that failed and the nodes that were cancelled because of them.
+    /// Each failure is only reported once, so waiting again after adding more nodes only reports what happened since.
+    pub fn wait(&self) -> Result<(), GraphError> {
+        self.shared.run_if_simulated();
+
+        let mut progress = lock(&self.state.progress);
+
+        while progress.pending > 0 {
+            progress = self.state.done.wait(progress).unwrap_or_else(PoisonError::into_inner);
+        }
+
+        if progress.failed.is_empty() && progress.cancelled.is_empty() {
+            return Ok(());
+        }
+
+        Err(GraphError {
+            failed: mem::take(&mut progress.failed),
+            cancelled: mem::take(&mut progress.cancelled),
+        })
+    }
+
+    /// The number of nodes that are waiting for their dependencies, queued or running.
+    pub fn pending(&self) -> usize {
+        lock(&self.state.progress).pending
+    }
+}
+
+impl fmt::Debug for TaskGraph {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let progress = lock(&self.state.progress);
+
+        f.debug_struct("TaskGraph")
+            .field("nodes", &progress.nodes.len())
+            .field("pending", &progress.pending)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, time::Duration};
+
+    use crate::{OverflowPolicy, ThreadPool};
+
+    #[test]
+    fn dependents_are_dispatched_when_the_queue_is_full() {
+        let pool = ThreadPool::builder()
+            .num_threads(1)
+            .queue_capacity(1)
+            .overflow_policy(OverflowPolicy::Block)
+            .build()
+            .unwrap();
+        let graph = pool.graph();
+        let (started, running) = mpsc::channel();
+        let (release, blocked) = mpsc::channel::<()>();
+
+        let first = graph.add("first", &[], move || {
+            started.send(()).unwrap();
+            let _ = blocked.recv();
+        });
+        graph.add("second", &[first], || {});
+        running.recv().unwrap();
+
+        // Fill the queue so there is no room for "second" when "first" finishes
+        pool.execute(|| {});
+        release.send(()).unwrap();
+
+        assert!(pool.wait_idle_timeout(Duration::from_secs(5)));
+        assert!(graph.wait().is_ok());
+    }
+}
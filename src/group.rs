@@ -0,0 +1,380 @@
+use std::{
+    convert::Infallible,
+    error::Error,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex, PoisonError},
+};
+
+use crate::{
+    lock,
+    queue::{JobInfo, Runnable},
+    CancellationToken, ExecuteError, GroupError, Priority, Shared,
+};
+
+/// A batch of related jobs that can be waited on and cancelled together, created by `ThreadPool::group`.
+///
+/// The jobs run on the pool alongside everything else, but [`wait`](JobGroup::wait) and
+/// [`cancel`](JobGroup::cancel) only look at the jobs in the group.
+/// Dropping the group neither waits for nor cancels its jobs.
+pub struct JobGroup {
+    shared: Arc<Shared>,
+    state: Arc<GroupState>,
+    token: CancellationToken,
+}
+
+/// How many of a [`JobGroup`]'s jobs have finished and how, from `JobGroup::counts`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct GroupCounts {
+    /// Jobs submitted to the group.
+    pub submitted: usize,
+    /// Jobs that ran and succeeded.
+    pub completed: usize,
+    /// Jobs that returned an error or panicked.
+    pub failed: usize,
+    /// Jobs that were dropped without running, such as the queued jobs of a cancelled group.
+    pub cancelled: usize,
+}
+
+impl GroupCounts {
+    /// Jobs that are queued or running.
+    pub fn pending(&self) -> usize {
+        self.submitted - self.completed - self.failed - self.cancelled
+    }
+}
+
+struct GroupState {
+    progress: Mutex<Progress>,
+    // Signalled when the last pending job finishes
+    done: Condvar,
+}
+
+struct Progress {
+    counts: GroupCounts,
+    // The first error or panic from any job, handed out by the next call to wait()
+    error: Option<GroupError>,
+}
+
+// Whatever keeps count of a group's or a graph's jobs, told how each one ended.
+// None means the job was dropped without running, because it was cancelled or the pool rejected it.
+pub(crate) trait Record {
+    type Output;
+
+    fn record(&self, result: Option<Result<(), GroupError>>) -> Self::Output;
+}
+
+// Lives inside each job's closure and records how the job ended, including that it never ran if it is dropped first
+pub(crate) struct Tracker<R: Record> {
+    recorder: R,
+    finished: bool,
+}
+
+impl<R: Record> Tracker<R> {
+    pub(crate) fn new(recorder: R) -> Tracker<R> {
+        Tracker { recorder, finished: false }
+    }
+
+    pub(crate) fn finish(mut self, result: Result<(), GroupError>) -> R::Output {
+        self.finished = true;
+        self.recorder.record(Some(result))
+    }
+
+    // For a job that is handed back to the caller instead of running, which isn't recorded at all
+    pub(crate) fn dismiss(mut self) {
+        self.finished = true;
+    }
+}
+
+impl<R: Record> Drop for Tracker<R> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.recorder.record(None);
+        }
+    }
+}
+
+// Runs a job that can fail, turning its error or panic into the GroupError handed out by wait()
+pub(crate) fn run_fallible<F, E>(f: F) -> Result<(), GroupError>
+where
+    F: FnOnce() -> Result<(), E>,
+    E: Into<Box<dyn Error + Send + Sync + 'static>>,
+{
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(GroupError::Failed(e.into())),
+        Err(payload) => Err(GroupError::Panicked(payload)),
+    }
+}
+
+impl Record for Arc<GroupState> {
+    type Output = ();
+
+    fn record(&self, result: Option<Result<(), GroupError>>) {
+        let mut progress = lock(&self.progress);
+
+        match result {
+            Some(Ok(())) => progress.counts.completed += 1,
+            Some(Err(e)) => {
+                progress.counts.failed += 1;
+                progress.error.get_or_insert(e);
+            }
+            None => progress.counts.cancelled += 1,
+        }
+
+        if progress.counts.pending() == 0 {
+            self.done.notify_all();
+        }
+    }
+}
+
+// A group job on its way to the pool. The caller's closure is kept apart from how it is run,
+// so it can be handed back if the pool rejects the job.
+struct GroupJob<F> {
+    f: F,
+    run: fn(F) -> Result<(), GroupError>,
+    tracker: Tracker<Arc<GroupState>>,
+}
+
+impl<F: Send + 'static> Runnable for GroupJob<F> {
+    fn run(self) {
+        self.tracker.finish((self.run)(self.f));
+    }
+}
+
+impl JobGroup {
+    pub(crate) fn new(shared: Arc<Shared>) -> JobGroup {
+        JobGroup {
+            shared,
+            state: Arc::new(GroupState {
+                progress: Mutex::new(Progress {
+                    counts: GroupCounts::default(),
+                    error: None,
+                }),
+                done: Condvar::new(),
+            }),
+            token: CancellationToken::new(),
+        }
+    }
+
+    /// Execute a job as part of the group.
+    ///
+    /// # Panics
+    ///
+    /// The `execute` function will panic if the pool rejects the job, see [`JobGroup::try_execute`].
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Err(e) = self.try_execute(f) {
+            panic!("failed to execute job: {e}");
+        }
+    }
+
+    /// Execute a job as part of the group, handing the closure back if the pool can't run it,
+    /// like `ThreadPool::try_execute`. A job that is handed back doesn't count towards the group.
+    pub fn try_execute<F>(&self, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.submit(f, |f| {
+            run_fallible(move || {
+                f();
+                Ok::<(), Infallible>(())
+            })
+        })
+    }
+
+    /// Execute a job that can fail as part of the group. The first error is returned by [`JobGroup::wait`].
+    ///
+    /// # Panics
+    ///
+    /// The `execute_fallible` function will panic if the pool rejects the job, see [`JobGroup::try_execute_fallible`].
+    pub fn execute_fallible<F, E>(&self, f: F)
+    where
+        F: FnOnce() -> Result<(), E> + Send + 'static,
+        E: Into<Box<dyn Error + Send + Sync + 'static>>,
+    {
+        if let Err(e) = self.try_execute_fallible(f) {
+            panic!("failed to execute job: {e}");
+        }
+    }
+
+    /// Execute a job that can fail as part of the group, handing the closure back if the pool can't run it,
+    /// like `ThreadPool::try_execute`. A job that is handed back doesn't count towards the group.
+    pub fn try_execute_fallible<F, E>(&self, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() -> Result<(), E> + Send + 'static,
+        E: Into<Box<dyn Error + Send + Sync + 'static>>,
+    {
+        self.submit(f, run_fallible)
+    }
+
+    fn submit<F: Send + 'static>(&self, f: F, run: fn(F) -> Result<(), GroupError>) -> Result<(), ExecuteError<F>> {
+        lock(&self.state.progress).counts.submitted += 1;
+
+        let job = GroupJob { f, run, tracker: Tracker::new(Arc::clone(&self.state)) };
+
+        // Tying the job to the group's token takes it off the queue if the group is cancelled
+        let info = JobInfo { token: Some(self.token.clone()), ..JobInfo::default() };
+
+        self.shared.try_execute_with(Priority::NORMAL, info, job).map_err(|e| {
+            e.map(|job| {
+                job.tracker.dismiss();
+                self.withdraw();
+                job.f
+            })
+        })
+    }
+
+    // Takes back a job that was counted as submitted, for a rejected job whose closure goes back to the caller
+    fn withdraw(&self) {
+        let mut progress = lock(&self.state.progress);
+        progress.counts.submitted -= 1;
+
+        if progress.counts.pending() == 0 {
+            self.state.done.notify_all();
+        }
+    }
+
+    /// Block until every job in the group has finished or been cancelled.
+    ///
+    /// Returns the first error or panic from any job, or [`GroupError::Cancelled`] if some jobs never ran because
+    /// the group was cancelled, or [`GroupError::Dropped`] if the pool dropped some without running them.
+    /// A job's error or panic is only returned once, so waiting again after more jobs have been submitted
+    /// only reports errors from those.
+    pub fn wait(&self) -> Result<(), GroupError> {
+        self.shared.run_if_simulated();
+
+        let mut progress = lock(&self.state.progress);
+
+        while progress.counts.pending() > 0 {
+            progress = self.state.done.wait(progress).unwrap_or_else(PoisonError::into_inner);
+        }
+
+        match progress.error.take() {
+            Some(e) => Err(e),
+            None if progress.counts.cancelled > 0 && self.token.is_cancelled() => Err(GroupError::Cancelled),
+            None if progress.counts.cancelled > 0 => Err(GroupError::Dropped),
+            None => Ok(()),
+        }
+    }
+
+    /// Cancel the group. Its queued jobs are dropped without running, and so are any jobs submitted afterwards.
+    ///
+    /// Jobs that are already running carry on unless they check the group's [`token`](JobGroup::token).
+    pub fn cancel(&self) {
+        self.token.cancel();
+    }
+
+    /// Returns `true` if the group has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// The token that is cancelled along with the group, for running jobs to check.
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+
+    /// How many of the group's jobs have been submitted, and how the finished ones ended.
+    pub fn counts(&self) -> GroupCounts {
+        lock(&self.state.progress).counts
+    }
+}
+
+impl fmt::Debug for JobGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JobGroup")
+            .field("counts", &self.counts())
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc, Arc,
+        },
+        time::Duration,
+    };
+
+    use crate::{GroupCounts, GroupError, OverflowPolicy, ThreadPool};
+
+    // A pool with a single worker that is kept busy until the returned sender is used or dropped
+    fn busy_pool(policy: OverflowPolicy) -> (ThreadPool, mpsc::Sender<()>) {
+        let pool = ThreadPool::builder()
+            .num_threads(1)
+            .queue_capacity(1)
+            .overflow_policy(policy)
+            .build()
+            .unwrap();
+        let (started, running) = mpsc::channel();
+        let (release, blocked) = mpsc::channel::<()>();
+
+        pool.execute(move || {
+            started.send(()).unwrap();
+            let _ = blocked.recv();
+        });
+        running.recv().unwrap();
+
+        (pool, release)
+    }
+
+    #[test]
+    fn try_execute_hands_the_closure_back() {
+        let pool = ThreadPool::new(1);
+        let group = pool.group();
+        pool.shutdown(Duration::from_secs(1));
+
+        let ran = Arc::new(AtomicUsize::new(0));
+        let inner = Arc::clone(&ran);
+        group
+            .try_execute(move || {
+                inner.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap_err()
+            .into_inner()();
+
+        let inner = Arc::clone(&ran);
+        let job = group.try_execute_fallible(move || {
+            inner.fetch_add(1, Ordering::SeqCst);
+            Ok::<(), io::Error>(())
+        });
+        assert!(job.unwrap_err().into_inner()().is_ok());
+        assert_eq!(ran.load(Ordering::SeqCst), 2);
+
+        // Jobs that were handed back never counted towards the group
+        assert_eq!(group.counts(), GroupCounts::default());
+        assert!(group.wait().is_ok());
+    }
+
+    #[test]
+    fn wait_reports_jobs_dropped_to_make_room() {
+        let (pool, release) = busy_pool(OverflowPolicy::DropOldest);
+        let group = pool.group();
+
+        group.execute(|| {});
+        pool.execute(|| {});
+        release.send(()).unwrap();
+
+        assert!(matches!(group.wait(), Err(GroupError::Dropped)));
+        assert_eq!(group.counts().cancelled, 1);
+    }
+
+    #[test]
+    fn wait_reports_jobs_dropped_at_the_shutdown_deadline() {
+        let (pool, release) = busy_pool(OverflowPolicy::Block);
+        let group = pool.group();
+
+        group.execute(|| {});
+        let report = pool.shutdown(Duration::from_millis(50));
+        assert_eq!(report.dropped_jobs, 1);
+
+        assert!(matches!(group.wait(), Err(GroupError::Dropped)));
+        drop(release);
+    }
+}
@@ -0,0 +1,172 @@
+use std::{
+    fmt,
+    sync::{Arc, Condvar, Mutex, PoisonError},
+    thread,
+    time::Duration,
+};
+
+use crate::{lock, JoinError};
+
+// The slot the job writes its result into and the handle reads it out of
+struct Packet<T> {
+    result: Mutex<Option<Result<T, JoinError>>>,
+    done: Condvar,
+}
+
+/// A handle to a job submitted with `ThreadPool::spawn`, used to wait for its return value.
+///
+/// Dropping the handle does not cancel the job, its result is simply thrown away.
+pub struct JobHandle<T> {
+    packet: Arc<Packet<T>>,
+}
+
+// The job's side of the packet. It lives inside the closure that is sent to the workers, so if the
+// closure is dropped without ever running (e.g. the pool rejected it) the handle is told about it.
+pub(crate) struct Completer<T> {
+    packet: Option<Arc<Packet<T>>>,
+}
+
+pub(crate) fn pair<T>() -> (Completer<T>, JobHandle<T>) {
+    let packet = Arc::new(Packet {
+        result: Mutex::new(None),
+        done: Condvar::new(),
+    });
+
+    (Completer { packet: Some(Arc::clone(&packet)) }, JobHandle { packet })
+}
+
+impl<T> Completer<T> {
+    pub(crate) fn complete(mut self, result: thread::Result<T>) {
+        self.finish(result.map_err(JoinError::Panicked));
+    }
+
+    fn finish(&mut self, result: Result<T, JoinError>) {
+        // take() so that Drop knows the result has already been delivered
+        if let Some(packet) = self.packet.take() {
+            *lock(&packet.result) = Some(result);
+            packet.done.notify_all();
+        }
+    }
+}
+
+impl<T> Drop for Completer<T> {
+    fn drop(&mut self) {
+        self.finish(Err(JoinError::Cancelled));
+    }
+}
+
+impl<T> JobHandle<T> {
+    /// Block until the job has finished and return its result.
+    ///
+    /// If the job panicked, the panic payload is returned in [`JoinError::Panicked`].
+    pub fn join(self) -> Result<T, JoinError> {
+        let mut result = lock(&self.packet.result);
+
+        // wait() releases the lock while we sleep and reacquires it when we are notified
+        while result.is_none() {
+            result = self.packet.done.wait(result).unwrap_or_else(PoisonError::into_inner);
+        }
+
+        result.take().unwrap()
+    }
+
+    /// Return the job's result if it has already finished, or give the handle back if it hasn't.
+    pub fn try_join(self) -> Result<Result<T, JoinError>, JobHandle<T>> {
+        let result = lock(&self.packet.result).take();
+
+        match result {
+            Some(result) => Ok(result),
+            None => Err(self),
+        }
+    }
+
+    /// Block until the job has finished or the timeout has elapsed.
+    ///
+    /// The handle is given back if the job is still running when the timeout expires.
+    pub fn join_timeout(self, timeout: Duration) -> Result<Result<T, JoinError>, JobHandle<T>> {
+        let result = lock(&self.packet.result);
+
+        // wait_timeout_while takes care of spurious wakeups and keeps track of the time remaining for us
+        let (mut result, _) = self
+            .packet
+            .done
+            .wait_timeout_while(result, timeout, |result| result.is_none())
+            .unwrap_or_else(PoisonError::into_inner);
+
+        match result.take() {
+            Some(result) => Ok(result),
+            None => {
+                drop(result);
+                Err(self)
+            }
+        }
+    }
+
+    /// Returns `true` once the job has finished, without blocking.
+    pub fn is_finished(&self) -> bool {
+        lock(&self.packet.result).is_some()
+    }
+}
+
+impl<T> fmt::Debug for JobHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JobHandle")
+            .field("finished", &self.is_finished())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, time::Duration};
+
+    use crate::{JoinError, ThreadPool};
+
+    #[test]
+    fn join_returns_the_value() {
+        let pool = ThreadPool::new(2);
+        let handle = pool.spawn(|| 6 * 7);
+
+        assert_eq!(handle.join().unwrap(), 42);
+    }
+
+    #[test]
+    fn try_join_and_join_timeout_hand_back_an_unfinished_handle() {
+        let pool = ThreadPool::new(1);
+        let (release, blocked) = mpsc::channel::<()>();
+
+        let handle = pool.spawn(move || {
+            let _ = blocked.recv();
+            "done"
+        });
+
+        let handle = handle.try_join().unwrap_err();
+        let handle = handle.join_timeout(Duration::from_millis(20)).unwrap_err();
+        assert!(!handle.is_finished());
+
+        release.send(()).unwrap();
+        assert_eq!(handle.join_timeout(Duration::from_secs(5)).unwrap().unwrap(), "done");
+    }
+
+    #[test]
+    fn panic_payload_is_passed_back() {
+        let pool = ThreadPool::new(1);
+        let handle = pool.spawn(|| -> u32 { panic!("job failed") });
+
+        match handle.join() {
+            Err(JoinError::Panicked(payload)) => assert_eq!(payload.downcast_ref::<&str>(), Some(&"job failed")),
+            other => panic!("expected a panic, got {other:?}"),
+        }
+
+        // The worker survives the panic
+        assert_eq!(pool.spawn(|| 1).join().unwrap(), 1);
+    }
+
+    #[test]
+    fn rejected_job_is_cancelled() {
+        let pool = ThreadPool::new(1);
+        pool.shutdown(Duration::from_secs(1));
+
+        assert!(matches!(pool.spawn(|| 1).join(), Err(JoinError::Cancelled)));
+    }
+}
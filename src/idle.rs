@@ -0,0 +1,66 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, PoisonError,
+    },
+    time::Instant,
+};
+
+use crate::lock;
+
+// Counts the jobs the pool has accepted but not yet finished, so callers can wait for it to go idle
+pub(crate) struct Idle {
+    pending: AtomicUsize,
+    // Only used with `idle`, to make sure a waiter that just saw pending jobs is already waiting when the last one finishes
+    lock: Mutex<()>,
+    // Signalled whenever pending drops to zero
+    idle: Condvar,
+}
+
+// Held by every accepted job until it has run or been dropped without running
+pub(crate) struct Pending(Arc<Idle>);
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        if self.0.pending.fetch_sub(1, Ordering::SeqCst) == 1 {
+            let _lock = lock(&self.0.lock);
+            self.0.idle.notify_all();
+        }
+    }
+}
+
+impl Idle {
+    pub(crate) fn new() -> Idle {
+        Idle {
+            pending: AtomicUsize::new(0),
+            lock: Mutex::new(()),
+            idle: Condvar::new(),
+        }
+    }
+
+    pub(crate) fn track(self: &Arc<Self>) -> Pending {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        Pending(Arc::clone(self))
+    }
+
+    // Blocks until there are no pending jobs or the deadline passes. Returns false if we gave up at the deadline.
+    pub(crate) fn wait(&self, deadline: Option<Instant>) -> bool {
+        let mut guard = lock(&self.lock);
+
+        while self.pending.load(Ordering::SeqCst) > 0 {
+            guard = match deadline {
+                None => self.idle.wait(guard).unwrap_or_else(PoisonError::into_inner),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+
+                    self.idle.wait_timeout(guard, deadline - now).unwrap_or_else(PoisonError::into_inner).0
+                }
+            };
+        }
+
+        true
+    }
+}
@@ -0,0 +1,309 @@
+use std::{
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    hash::{BuildHasher, Hash, RandomState},
+    panic::{self, AssertUnwindSafe},
+    sync::{Mutex, Weak},
+};
+
+use crate::{
+    lock,
+    logging::event,
+    queue::{JobInfo, Runnable},
+    Job, Shared,
+};
+
+// The jobs submitted with `ThreadPool::execute_keyed`, one lane per key.
+//
+// A lane exists while it has a job queued on the pool or running, and holds the jobs waiting their turn behind it.
+// Only one job per lane is ever on the pool's queue, so jobs with the same key can't run at the same time, and
+// each job submits the next one in its lane when it finishes, which keeps them in order. Keys are hashed, so two
+// keys that happen to collide share a lane: that costs them some parallelism but never the ordering.
+pub(crate) struct Lanes {
+    lanes: Mutex<HashMap<u64, VecDeque<Job>>>,
+    hasher: RandomState,
+}
+
+impl Lanes {
+    pub(crate) fn new() -> Lanes {
+        Lanes {
+            lanes: Mutex::new(HashMap::new()),
+            hasher: RandomState::new(),
+        }
+    }
+
+    pub(crate) fn hash<K: Hash>(&self, key: &K) -> u64 {
+        self.hasher.hash_one(key)
+    }
+
+    // Adds a job to the back of its lane. If the lane was empty it is claimed and the job handed back instead,
+    // in which case the caller has to start the lane with it, see `LaneStart`.
+    pub(crate) fn push<F>(&self, lane: u64, f: F) -> Option<F>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match lock(&self.lanes).entry(lane) {
+            Entry::Occupied(mut entry) => {
+                entry.get_mut().push_back(Box::new(f));
+                None
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(VecDeque::new());
+                Some(f)
+            }
+        }
+    }
+
+    // Takes back a lane that couldn't be started, along with anything queued behind it in the meantime
+    pub(crate) fn remove(&self, lane: u64) -> Option<VecDeque<Job>> {
+        lock(&self.lanes).remove(&lane)
+    }
+
+    fn front(&self, lane: u64) -> Option<Job> {
+        lock(&self.lanes).get_mut(&lane)?.pop_front()
+    }
+
+    // Called once a job has finished. Returns true if the lane has more jobs, or removes it and returns false.
+    fn finished(&self, lane: u64) -> bool {
+        let mut lanes = lock(&self.lanes);
+
+        match lanes.get(&lane) {
+            Some(jobs) if !jobs.is_empty() => true,
+            _ => {
+                lanes.remove(&lane);
+                false
+            }
+        }
+    }
+}
+
+// The first job queued for a lane that was empty. It is kept apart from the lane until it runs,
+// so the caller's closure can be handed back if the pool rejects it.
+pub(crate) struct LaneStart<F> {
+    pub(crate) f: F,
+    pub(crate) pool: Weak<Shared>,
+    pub(crate) lane: u64,
+}
+
+impl<F: FnOnce() + Send + 'static> Runnable for LaneStart<F> {
+    fn run(self) {
+        run_lane(&self.pool, self.lane, Some(self.f.into_job()));
+    }
+}
+
+// The job that is queued on the pool for a lane: it runs the lane's next job, then queues itself again if there are more.
+// It only holds a Weak to the pool, so a lane waiting in the queue doesn't keep the pool alive.
+fn run_lane(pool: &Weak<Shared>, lane: u64, mut first: Option<Job>) {
+    let Some(shared) = pool.upgrade() else { return };
+    let mut panicked = None;
+
+    loop {
+        let Some(job) = first.take().or_else(|| shared.lanes.front(lane)) else { break };
+
+        // The panic is resumed once the next job is on its way, so the worker still reports it
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+            match panicked {
+                None => panicked = Some(payload),
+                // Only one panic can be resumed, which only matters when we ran several jobs in a row below
+                Some(_) => event!(error, lane = lane; "keyed job panicked"),
+            }
+        }
+
+        if !shared.lanes.finished(lane) {
+            break;
+        }
+
+        // Queue the next job rather than running it here, so other jobs get a turn in between
+        let pool = Weak::clone(pool);
+        if shared.requeue(JobInfo::default(), move || run_lane(&pool, lane, None)).is_ok() {
+            break;
+        }
+
+        // The pool is shutting down and won't take it, so we carry on with it ourselves
+    }
+
+    if let Some(payload) = panicked {
+        panic::resume_unwind(payload);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc, Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    use crate::{Backend, ExecuteError, OverflowPolicy, Priority, ThreadPool};
+
+    // Runs `jobs` jobs under one key on a single worker whose queue is kept full by another job,
+    // so every time the lane queues its next job there is no room for it
+    fn run_lane_on_full_queue(policy: OverflowPolicy, jobs: usize) -> (ThreadPool, Arc<Mutex<usize>>) {
+        let pool = ThreadPool::builder()
+            .num_threads(1)
+            .queue_capacity(1)
+            .overflow_policy(policy)
+            .build()
+            .unwrap();
+        let seen = Arc::new(Mutex::new(0));
+        let (started, running) = mpsc::channel();
+        let (release, blocked) = mpsc::channel::<()>();
+
+        pool.execute_keyed(0, move || {
+            started.send(()).unwrap();
+            let _ = blocked.recv();
+        });
+        running.recv().unwrap();
+
+        pool.execute(|| {});
+
+        for _ in 0..jobs {
+            let seen = Arc::clone(&seen);
+            pool.execute_keyed(0, move || *seen.lock().unwrap() += 1);
+        }
+        release.send(()).unwrap();
+
+        (pool, seen)
+    }
+
+    #[test]
+    fn jobs_with_the_same_key_run_in_order() {
+        let pool = ThreadPool::new(4);
+        let seen: Arc<Mutex<HashMap<u32, Vec<u32>>>> = Arc::default();
+
+        for i in 0..400 {
+            let seen = Arc::clone(&seen);
+            pool.execute_keyed(i % 8, move || seen.lock().unwrap().entry(i % 8).or_default().push(i));
+        }
+
+        assert!(pool.wait_idle_timeout(Duration::from_secs(5)));
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.values().map(Vec::len).sum::<usize>(), 400);
+        assert!(seen.values().all(|jobs| jobs.windows(2).all(|pair| pair[0] < pair[1])));
+    }
+
+    #[test]
+    fn full_blocking_queue_does_not_deadlock() {
+        let (pool, seen) = run_lane_on_full_queue(OverflowPolicy::Block, 100);
+
+        assert!(pool.wait_idle_timeout(Duration::from_secs(5)));
+        assert_eq!(*seen.lock().unwrap(), 100);
+    }
+
+    #[test]
+    fn caller_runs_does_not_recurse_through_a_long_lane() {
+        let (pool, seen) = run_lane_on_full_queue(OverflowPolicy::CallerRuns, 200_000);
+
+        assert!(pool.wait_idle_timeout(Duration::from_secs(10)));
+        assert_eq!(*seen.lock().unwrap(), 200_000);
+    }
+
+    #[test]
+    fn lane_finishes_when_the_pool_is_dropped() {
+        let pool = ThreadPool::new(2);
+        let seen = Arc::new(Mutex::new(0));
+
+        for _ in 0..100 {
+            let seen = Arc::clone(&seen);
+            pool.execute_keyed(0, move || *seen.lock().unwrap() += 1);
+        }
+        drop(pool);
+
+        assert_eq!(*seen.lock().unwrap(), 100);
+    }
+
+    #[test]
+    fn rejected_keyed_job_frees_its_key() {
+        let pool = ThreadPool::builder()
+            .num_threads(1)
+            .queue_capacity(1)
+            .overflow_policy(OverflowPolicy::Reject)
+            .build()
+            .unwrap();
+        let (release, blocked) = mpsc::channel::<()>();
+        let (started, running) = mpsc::channel();
+
+        pool.execute(move || {
+            started.send(()).unwrap();
+            let _ = blocked.recv();
+        });
+        running.recv().unwrap();
+        pool.execute(|| {});
+
+        let ran = Arc::new(AtomicUsize::new(0));
+        let inner = Arc::clone(&ran);
+        let rejected = pool.try_execute_keyed(0, move || {
+            inner.fetch_add(1, Ordering::SeqCst);
+        });
+        assert!(matches!(rejected, Err(ExecuteError::QueueFull(_))));
+
+        release.send(()).unwrap();
+        assert!(pool.wait_idle_timeout(Duration::from_secs(5)));
+
+        // The key isn't held by the rejected job, so the next one runs
+        rejected.unwrap_err().into_inner()();
+        let inner = Arc::clone(&ran);
+        pool.try_execute_keyed(0, move || {
+            inner.fetch_add(1, Ordering::SeqCst);
+        })
+        .unwrap();
+
+        assert!(pool.wait_idle_timeout(Duration::from_secs(5)));
+        assert_eq!(ran.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn drop_oldest_does_not_drop_the_next_job_of_a_key() {
+        for backend in [Backend::Channel, Backend::WorkStealing] {
+            let pool = ThreadPool::builder()
+                .num_threads(1)
+                .queue_capacity(1)
+                .overflow_policy(OverflowPolicy::DropOldest)
+                .backend(backend)
+                .build()
+                .unwrap();
+            let ran = Arc::new(AtomicUsize::new(0));
+            let (started, running) = mpsc::channel();
+            let (release_first, first_blocked) = mpsc::channel::<()>();
+            let (release_busy, busy_blocked) = mpsc::channel::<()>();
+
+            let first_started = started.clone();
+            pool.execute_keyed(0, move || {
+                first_started.send(()).unwrap();
+                let _ = first_blocked.recv();
+            });
+            running.recv().unwrap();
+
+            let inner = Arc::clone(&ran);
+            pool.execute_keyed(0, move || {
+                inner.fetch_add(1, Ordering::SeqCst);
+            });
+
+            // Keeps the worker busy once the first keyed job is done and has queued the second
+            pool.execute_with_priority(Priority::HIGH, move || {
+                started.send(()).unwrap();
+                let _ = busy_blocked.recv();
+            });
+            release_first.send(()).unwrap();
+            running.recv().unwrap();
+
+            // The queue is full, but the only other job in it is the key's next one
+            pool.execute(|| {});
+            release_busy.send(()).unwrap();
+            assert!(pool.wait_idle_timeout(Duration::from_secs(5)));
+            assert_eq!(ran.load(Ordering::SeqCst), 1, "{backend:?}");
+
+            // The key isn't stranded either
+            let inner = Arc::clone(&ran);
+            pool.execute_keyed(0, move || {
+                inner.fetch_add(1, Ordering::SeqCst);
+            });
+            assert!(pool.wait_idle_timeout(Duration::from_secs(5)));
+            assert_eq!(ran.load(Ordering::SeqCst), 2, "{backend:?}");
+        }
+    }
+}
@@ -1,17 +1,150 @@
 use std::{
-    sync::{mpsc, Arc, Mutex},
+    any::Any,
+    future::Future,
+    hash::Hash,
+    io, mem,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, OnceLock, PoisonError,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
+mod affinity;
+mod builder;
+mod cancel;
+mod error;
+mod graph;
+mod group;
+mod handle;
+mod idle;
+mod keyed;
+mod logging;
+mod parallel;
+mod pool_handle;
+mod priority;
+mod queue;
+mod scheduler;
+mod scope;
+mod simulation;
+mod stats;
+mod stealing;
+mod task;
+mod timer;
+mod watchdog;
+
+pub use affinity::CoreAffinity;
+pub use builder::ThreadPoolBuilder;
+pub use cancel::CancellationToken;
+pub use error::{ExecuteError, GraphError, GroupError, JoinError, NodeFailure, PoolCreationError};
+pub use graph::{NodeId, TaskGraph};
+pub use group::{GroupCounts, JobGroup};
+pub use handle::JobHandle;
+pub use pool_handle::PoolHandle;
+pub use priority::Priority;
+pub use queue::OverflowPolicy;
+pub use scheduler::Backend;
+pub use scope::Scope;
+pub use stats::{Histogram, PoolStats};
+pub use task::block_on;
+pub use timer::ScheduleHandle;
+pub use watchdog::StuckJob;
+
+use cancel::TokenJob;
+use idle::Idle;
+use keyed::{LaneStart, Lanes};
+use logging::event;
+use queue::{JobInfo, Pop, PushError, Queued, Runnable};
+use scheduler::Scheduler;
+use stats::Metrics;
+use timer::Timer;
+use watchdog::{Activity, Watchdog};
+
+/// A job waiting to be run by the pool, as handed back by [`ThreadPool::shutdown_now`].
 // This is a type alias for a trait object that holds the type of closure that execute receives. 
 // Type aliases makes it easier to re-use long types
-type Job = Box<dyn FnOnce() + Send + 'static>;
+pub type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// Called with the worker id and panic payload whenever a job panics
+type PanicHandler = dyn Fn(usize, Box<dyn Any + Send>) + Send + Sync + 'static;
 
+// Turns a worker id into a thread name
+type ThreadNamer = dyn Fn(usize) -> String + Send + Sync + 'static;
+
+// Called with the worker id on the worker thread as it starts or stops
+type ThreadHook = dyn Fn(usize) + Send + Sync + 'static;
+
+// Called by the watchdog with each job that goes over its time budget
+type StuckJobHandler = dyn Fn(&StuckJob) + Send + Sync + 'static;
+
+// Locks one of the pool's mutexes. Nothing the pool keeps behind a lock is left half updated if a panic happens while
+// it is held, so a poisoned lock is safe to keep using rather than taking every worker down with it.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
 
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
+    shared: Arc<Shared>,
+}
+
+/// What happened to the pool's work during [`ThreadPool::shutdown`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// Ids of the workers that were still running when the timeout ran out, which is almost always because they were
+    /// in the middle of a job. They are left to finish that job in the background and then exit.
+    pub unfinished_workers: Vec<usize>,
+    /// The number of queued jobs that were thrown away because the timeout ran out before they were started.
+    pub dropped_jobs: usize,
+}
+
+impl ShutdownReport {
+    /// Returns `true` if every job finished before the timeout.
+    pub fn is_complete(&self) -> bool {
+        self.unfinished_workers.is_empty() && self.dropped_jobs == 0
+    }
+}
 
+// Everything the pool and its workers need access to.
+// Workers hold on to this so they can replace themselves if they die.
+struct Shared {
+    // The queue is shared between the pool, which pushes jobs, and the workers, which pop them
+    queue: Scheduler,
+    // Behind a Mutex because workers add their own replacements to it
+    workers: Mutex<Vec<Worker>>,
+    // Signalled, with the workers lock held, whenever a worker thread exits or gets through its start hook
+    worker_exited: Condvar,
+    // Hands delayed and periodic jobs to the queue when they are due
+    timer: Timer,
+    metrics: Metrics,
+    // Number of worker threads that are still running, plus any that are about to be started
+    live_workers: AtomicUsize,
+    // Workers left running in the background by a shutdown that timed out, which nobody waits for any more
+    detached_workers: AtomicUsize,
+    // Used to give every worker started after the initial ones its own id
+    next_id: AtomicUsize,
+    // Workers that have been spawned but haven't got through their start hook yet
+    starting_workers: AtomicUsize,
+    // Workers that died before they got through their start hook, which are never replaced
+    failed_starts: AtomicUsize,
+    min_threads: usize,
+    max_threads: usize,
+    keep_alive: Duration,
+    panic_handler: Option<Arc<PanicHandler>>,
+    thread_name: Option<Arc<ThreadNamer>>,
+    stack_size: Option<usize>,
+    on_thread_start: Option<Arc<ThreadHook>>,
+    on_thread_stop: Option<Arc<ThreadHook>>,
+    // Workers are pinned to these cores in turn by id, None if they aren't pinned
+    pinned_cores: Option<Vec<usize>>,
+    watchdog: Watchdog,
+    job_budget: Option<Duration>,
+    stuck_job_handler: Option<Arc<StuckJobHandler>>,
+    replace_stuck_workers: bool,
+    idle: Arc<Idle>,
+    // Jobs submitted with execute_keyed that are waiting for the job before them with the same key
+    lanes: Lanes,
 }
 
 impl ThreadPool {
@@ -21,90 +154,1428 @@ impl ThreadPool {
     ///
     /// # Panics
     ///
-    /// The `new` function will panic if the size is zero.
+    /// The `new` function will panic if the size is zero or if a worker thread could not be spawned.
+    /// Use [`ThreadPool::build`] to handle those cases instead.
     pub fn new(size: usize) -> ThreadPool {
-        assert!(size > 0);
+        match ThreadPool::build(size) {
+            Ok(pool) => pool,
+            Err(e) => panic!("failed to create thread pool: {e}"),
+        }
+    }
+
+    /// Create a new ThreadPool, returning an error instead of panicking.
+    ///
+    /// The size is the number of threads in the pool.
+    ///
+    /// If the operating system refuses to spawn one of the workers, the workers that were
+    /// already started are shut down and joined before the error is returned.
+    pub fn build(size: usize) -> Result<ThreadPool, PoolCreationError> {
+        ThreadPoolBuilder::new().num_threads(size).build()
+    }
+
+    /// Create a [`ThreadPoolBuilder`] to configure a pool beyond its size.
+    pub fn builder() -> ThreadPoolBuilder {
+        ThreadPoolBuilder::new()
+    }
+
+    pub(crate) fn start(builder: ThreadPoolBuilder) -> Result<ThreadPool, PoolCreationError> {
+        let ThreadPoolBuilder { min_threads, max_threads, .. } = builder;
+
+        if max_threads == 0 {
+            return Err(PoolCreationError::ZeroSize);
+        }
+
+        if min_threads > max_threads {
+            return Err(PoolCreationError::InvalidThreadRange { min: min_threads, max: max_threads });
+        }
+
+        if builder.queue_capacity == Some(0) {
+            return Err(PoolCreationError::ZeroQueueCapacity);
+        }
+
+        let pinned_cores = match &builder.core_affinity {
+            Some(affinity) => affinity::resolve(affinity)?,
+            None => None,
+        };
 
-        // the channel implementation that Rust provides is multiple producer, single consumer
-        let (sender, receiver) = mpsc::channel();
-       
-        // to share ownership across multiple threads and allow the threads to mutate the value, we need to use Arc<Mutex<T>>
-        // The Arc type will let multiple workers own the receiver
-        // Mutex will ensure that only one worker gets a job from the receiver at a time.
-        let receiver = Arc::new(Mutex::new(receiver));
-        
-        let mut workers = Vec::with_capacity(size);
+        // to share ownership across multiple threads, we need to use Arc<T>
+        // The Arc type will let multiple workers own the shared state, the queue takes care of its own locking
+        let pool = ThreadPool {
+            shared: Arc::new(Shared {
+                queue: Scheduler::new(
+                    builder.backend,
+                    builder.queue_capacity,
+                    builder.overflow_policy,
+                    builder.priority_aging,
+                ),
+                workers: Mutex::new(Vec::with_capacity(min_threads)),
+                worker_exited: Condvar::new(),
+                timer: Timer::new(),
+                metrics: Metrics::new(),
+                live_workers: AtomicUsize::new(0),
+                detached_workers: AtomicUsize::new(0),
+                next_id: AtomicUsize::new(0),
+                starting_workers: AtomicUsize::new(0),
+                failed_starts: AtomicUsize::new(0),
+                min_threads,
+                max_threads,
+                keep_alive: builder.keep_alive,
+                panic_handler: builder.panic_handler,
+                thread_name: builder.thread_name,
+                stack_size: builder.stack_size,
+                on_thread_start: builder.on_thread_start,
+                on_thread_stop: builder.on_thread_stop,
+                pinned_cores,
+                watchdog: Watchdog::new(),
+                job_budget: builder.job_budget,
+                stuck_job_handler: builder.stuck_job_handler,
+                replace_stuck_workers: builder.replace_stuck_workers,
+                idle: Arc::new(Idle::new()),
+                lanes: Lanes::new(),
+            }),
+        };
 
-        for id in 0..size {
-            // For each new worker, we clone the Arc to bump the reference count so the workers can share ownership of the receiver.
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+        if pool.shared.job_budget.is_some() {
+            pool.shared.watchdog.start(&pool.shared).map_err(PoolCreationError::WatchdogFailed)?;
         }
 
-        ThreadPool { workers, sender: Some(sender) }
+        for _ in 0..min_threads {
+            if let Err(source) = pool.shared.add_worker() {
+                let started = pool.shared.live_workers.load(Ordering::SeqCst);
+
+                // dropping the partially built pool closes the queue and joins the workers we already started
+                drop(pool);
+
+                return Err(PoolCreationError::SpawnFailed { requested: min_threads, started, source });
+            }
+        }
+
+        // A worker that panics in its start hook isn't replaced, as the replacement would only panic in the same place.
+        // So we wait for the initial workers to get going, rather than hand back a pool that may have none.
+        if !pool.shared.wait_for_startup() {
+            drop(pool);
+            return Err(PoolCreationError::WorkerStartPanicked);
+        }
+
+        Ok(pool)
     }
 
+    /// Execute a job on one of the pool's threads.
+    ///
+    /// # Panics
+    ///
+    /// The `execute` function will panic if the job is rejected, see [`ThreadPool::try_execute`].
     pub fn execute<F>(&self, f: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        let job = Box::new(f);
+        if let Err(e) = self.try_execute(f) {
+            panic!("failed to execute job: {e}");
+        }
+    }
+
+    /// Execute a job on one of the pool's threads, handing the closure back if the pool can't run it.
+    ///
+    /// If the queue is full, what happens depends on the pool's [`OverflowPolicy`]: this may block,
+    /// return [`ExecuteError::QueueFull`], drop the oldest queued job or run `f` on the calling thread.
+    pub fn try_execute<F>(&self, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.try_execute_with_priority(Priority::NORMAL, f)
+    }
+
+    /// Execute a job ahead of (or behind) other queued jobs, depending on its [`Priority`].
+    ///
+    /// Workers always start the queued job with the highest priority first. Jobs gain priority the longer
+    /// they wait, see [`ThreadPoolBuilder::priority_aging`], so low priority jobs still get to run eventually.
+    ///
+    /// # Panics
+    ///
+    /// The `execute_with_priority` function will panic if the job is rejected, see [`ThreadPool::try_execute`].
+    pub fn execute_with_priority<F>(&self, priority: Priority, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Err(e) = self.try_execute_with_priority(priority, f) {
+            panic!("failed to execute job: {e}");
+        }
+    }
+
+    /// Execute a job with the given [`Priority`], handing the closure back if the pool can't run it.
+    pub fn try_execute_with_priority<F>(&self, priority: Priority, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.shared.try_execute(priority, f)
+    }
+
+    /// Run a job on the pool and get a handle that can be used to wait for its return value.
+    ///
+    /// A panic inside the job is caught and handed to whoever joins the handle rather than taking down the worker.
+    /// If the pool rejects the job, joining the handle returns [`JoinError::Cancelled`].
+    pub fn spawn<F, T>(&self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.shared.spawn(f)
+    }
+
+    /// Get a [`PoolHandle`] that can submit jobs to the pool from any thread, including from inside jobs.
+    ///
+    /// Handles stop being able to submit jobs once the pool starts shutting down, see [`PoolHandle`].
+    pub fn handle(&self) -> PoolHandle {
+        PoolHandle::new(Arc::clone(&self.shared))
+    }
+
+    /// Execute a job with a label that identifies it to the watchdog, see [`ThreadPoolBuilder::job_budget`].
+    ///
+    /// # Panics
+    ///
+    /// The `execute_labeled` function will panic if the job is rejected, see [`ThreadPool::try_execute_labeled`].
+    pub fn execute_labeled<F>(&self, label: &str, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Err(e) = self.try_execute_labeled(label, f) {
+            panic!("failed to execute job: {e}");
+        }
+    }
+
+    /// Execute a job with a label, handing the closure back if the pool can't run it, like [`ThreadPool::try_execute`].
+    pub fn try_execute_labeled<F>(&self, label: &str, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let info = JobInfo { label: Some(label.into()), ..JobInfo::default() };
+        self.shared.try_execute_with(Priority::NORMAL, info, f)
+    }
+
+    /// Execute a job that the watchdog reports if it runs for longer than `budget`,
+    /// instead of the pool-wide [`job_budget`](ThreadPoolBuilder::job_budget).
+    ///
+    /// The watchdog thread is started the first time a job is given a budget, if it isn't running already.
+    ///
+    /// # Panics
+    ///
+    /// The `execute_with_budget` function will panic if the job is rejected or the watchdog thread
+    /// could not be spawned, see [`ThreadPool::try_execute_with_budget`].
+    pub fn execute_with_budget<F>(&self, label: &str, budget: Duration, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Err(e) = self.try_execute_with_budget(label, budget, f) {
+            panic!("failed to execute job: {e}");
+        }
+    }
+
+    /// Execute a job with a time budget, handing the closure back if the pool can't run it,
+    /// like [`ThreadPool::try_execute`].
+    ///
+    /// If the watchdog thread isn't running and can't be spawned, the job isn't submitted and
+    /// [`ExecuteError::WatchdogFailed`] is returned instead.
+    pub fn try_execute_with_budget<F>(&self, label: &str, budget: Duration, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Err(e) = self.shared.watchdog.start(&self.shared) {
+            return Err(ExecuteError::WatchdogFailed(f, e));
+        }
+
+        let info = JobInfo { label: Some(label.into()), budget: Some(budget), ..JobInfo::default() };
+        self.shared.try_execute_with(Priority::NORMAL, info, f)
+    }
+
+    /// Execute a job that can be cancelled, returning the token that cancels it.
+    ///
+    /// The job is handed the token so it can check whether it should stop, see [`CancellationToken`].
+    ///
+    /// # Panics
+    ///
+    /// The `execute_cancellable` function will panic if the job is rejected,
+    /// see [`ThreadPool::try_execute_cancellable`].
+    pub fn execute_cancellable<F>(&self, f: F) -> CancellationToken
+    where
+        F: FnOnce(&CancellationToken) + Send + 'static,
+    {
+        match self.try_execute_cancellable(f) {
+            Ok(token) => token,
+            Err(e) => panic!("failed to execute job: {e}"),
+        }
+    }
+
+    /// Execute a job that can be cancelled, handing the closure back if the pool can't run it,
+    /// like [`ThreadPool::try_execute`].
+    pub fn try_execute_cancellable<F>(&self, f: F) -> Result<CancellationToken, ExecuteError<F>>
+    where
+        F: FnOnce(&CancellationToken) + Send + 'static,
+    {
+        let token = CancellationToken::new();
+        self.try_execute_with_token(&token, f)?;
+        Ok(token)
+    }
+
+    /// Execute a job tied to an existing token, such as a [child](CancellationToken::child_token)
+    /// of a token that covers a whole request.
+    ///
+    /// # Panics
+    ///
+    /// The `execute_with_token` function will panic if the job is rejected, see [`ThreadPool::try_execute_with_token`].
+    pub fn execute_with_token<F>(&self, token: &CancellationToken, f: F)
+    where
+        F: FnOnce(&CancellationToken) + Send + 'static,
+    {
+        if let Err(e) = self.try_execute_with_token(token, f) {
+            panic!("failed to execute job: {e}");
+        }
+    }
+
+    /// Execute a job tied to an existing token, handing the closure back if the pool can't run it,
+    /// like [`ThreadPool::try_execute`].
+    ///
+    /// A job whose token is already cancelled is dropped without running, and this still returns `Ok`.
+    pub fn try_execute_with_token<F>(&self, token: &CancellationToken, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce(&CancellationToken) + Send + 'static,
+    {
+        let job = TokenJob { f, token: token.clone() };
+        let info = JobInfo { token: Some(token.clone()), ..JobInfo::default() };
+
+        self.shared.try_execute_with(Priority::NORMAL, info, job).map_err(|e| e.map(|job| job.f))
+    }
+
+    /// Execute a job that runs after every job submitted earlier with the same key has finished.
+    ///
+    /// Jobs with the same key run one at a time, in the order they were submitted, while jobs with different keys
+    /// run in parallel on any of the workers. No worker is tied to a key: only the next job for each key is put on
+    /// the queue, and it queues the one after it when it is done. A job that panics doesn't hold up the rest of its key.
+    ///
+    /// # Panics
+    ///
+    /// The `execute_keyed` function will panic if the job is rejected, see [`ThreadPool::try_execute_keyed`].
+    pub fn execute_keyed<K, F>(&self, key: K, f: F)
+    where
+        K: Hash,
+        F: FnOnce() + Send + 'static,
+    {
+        if let Err(e) = self.try_execute_keyed(key, f) {
+            panic!("failed to execute job: {e}");
+        }
+    }
+
+    /// Execute a keyed job, handing the closure back if the pool can't run it, like [`ThreadPool::try_execute`].
+    ///
+    /// Only a job that would be the next to run for its key goes on the queue, so only it can be rejected.
+    /// A job queued behind an earlier one with the same key is always accepted, and if that earlier job is rejected,
+    /// the jobs queued behind it in the meantime are dropped along with it.
+    pub fn try_execute_keyed<K, F>(&self, key: K, f: F) -> Result<(), ExecuteError<F>>
+    where
+        K: Hash,
+        F: FnOnce() + Send + 'static,
+    {
+        let lane = self.shared.lanes.hash(&key);
+
+        // If there is already a job queued or running for the key, it will queue this one when its turn comes
+        let Some(f) = self.shared.lanes.push(lane, f) else {
+            return Ok(());
+        };
+
+        let start = LaneStart { f, pool: Arc::downgrade(&self.shared), lane };
+        self.shared.try_execute_with(Priority::NORMAL, JobInfo::default(), start).map_err(|e| {
+            // Nothing is going to run the lane, so anything added behind ours meanwhile is dropped
+            drop(self.shared.lanes.remove(lane));
+            e.map(|start| start.f)
+        })
+    }
+
+    /// Run a future on the pool and get a handle that can be used to wait for its output.
+    ///
+    /// Each poll of the future runs as a job on one of the workers. When the future is woken it is put back
+    /// on the queue to be polled again, so futures share the workers with ordinary jobs without blocking them
+    /// while they wait. A panic while polling is handed to whoever joins the handle, and if the pool stops
+    /// accepting jobs before the future is done, joining returns [`JoinError::Cancelled`].
+    ///
+    /// There is no reactor for timers or IO, so the future has to be woken by something else,
+    /// such as another job or thread. See [`block_on`] for waiting on a future outside the pool.
+    pub fn spawn_future<F>(&self, future: F) -> JobHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (completer, handle) = handle::pair();
+        task::Task::spawn(&self.shared, future, completer);
+        handle
+    }
+
+    /// Create a [`JobGroup`] for submitting a batch of related jobs that can be waited on and cancelled together.
+    pub fn group(&self) -> JobGroup {
+        JobGroup::new(Arc::clone(&self.shared))
+    }
+
+    /// Create a [`TaskGraph`] for running jobs that depend on each other, each one as soon as its dependencies are done.
+    pub fn graph(&self) -> TaskGraph {
+        TaskGraph::new(Arc::clone(&self.shared))
+    }
+
+    /// Run `f` with a [`Scope`] that can execute jobs borrowing from the current stack frame.
+    ///
+    /// Jobs run on the pool's workers like any other job, and `scope` doesn't return until all of them
+    /// have finished, so they can borrow anything that outlives the call.
+    ///
+    /// Calling `scope` from inside one of the pool's own jobs ties up that worker while it waits,
+    /// so it can deadlock if every worker ends up waiting on a scope.
+    ///
+    /// # Panics
+    ///
+    /// If `f` or any of the jobs panic, `scope` waits for the remaining jobs to finish and then
+    /// resumes the panic from `f`, or failing that the first panic from a job.
+    pub fn scope<'env, F, T>(&'env self, f: F) -> T
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+    {
+        scope::scope(self, f)
+    }
+
+    /// Apply `f` to every item on the pool and collect the results, in the same order as the items.
+    ///
+    /// The items are split into a few chunks per worker, see [`ThreadPool::map_chunked`] to pick the chunk size.
+    /// Like [`ThreadPool::scope`], neither the items nor `f` need to be `'static`.
+    ///
+    /// # Panics
+    ///
+    /// If `f` panics, the chunks that haven't got to the end stop early and the first panic is resumed on the caller.
+    pub fn map<I, F, T>(&self, items: I, f: F) -> Vec<T>
+    where
+        I: IntoIterator,
+        I::Item: Send,
+        F: Fn(I::Item) -> T + Sync,
+        T: Send,
+    {
+        let items: Vec<_> = items.into_iter().collect();
+        let chunk_size = parallel::default_chunk_size(self, items.len());
+        parallel::map_chunked(self, items, chunk_size, f)
+    }
+
+    /// Like [`ThreadPool::map`], with every job working through `chunk_size` items.
+    ///
+    /// Bigger chunks cost less overhead per item, smaller ones spread uneven work more evenly over the workers.
+    ///
+    /// # Panics
+    ///
+    /// The `map_chunked` function will panic if `chunk_size` is zero, or resume the first panic from `f`.
+    pub fn map_chunked<I, F, T>(&self, items: I, chunk_size: usize, f: F) -> Vec<T>
+    where
+        I: IntoIterator,
+        I::Item: Send,
+        F: Fn(I::Item) -> T + Sync,
+        T: Send,
+    {
+        parallel::map_chunked(self, items, chunk_size, f)
+    }
+
+    /// Call `f` with every item on the pool, returning once all the calls have finished.
+    ///
+    /// # Panics
+    ///
+    /// If `f` panics, the chunks that haven't got to the end stop early and the first panic is resumed on the caller.
+    pub fn for_each<I, F>(&self, items: I, f: F)
+    where
+        I: IntoIterator,
+        I::Item: Send,
+        F: Fn(I::Item) + Sync,
+    {
+        self.map(items, f);
+    }
+
+    /// Like [`ThreadPool::for_each`], with every job working through `chunk_size` items.
+    ///
+    /// # Panics
+    ///
+    /// The `for_each_chunked` function will panic if `chunk_size` is zero, or resume the first panic from `f`.
+    pub fn for_each_chunked<I, F>(&self, items: I, chunk_size: usize, f: F)
+    where
+        I: IntoIterator,
+        I::Item: Send,
+        F: Fn(I::Item) + Sync,
+    {
+        parallel::map_chunked(self, items, chunk_size, f);
+    }
+
+    /// Run a job on the pool once `delay` has passed.
+    ///
+    /// A single timer thread, started the first time something is scheduled, waits for the job to be due
+    /// and then submits it like [`ThreadPool::execute`]. Jobs that are still waiting when the pool is
+    /// dropped never run.
+    ///
+    /// # Panics
+    ///
+    /// The `schedule_after` function will panic if the pool is shutting down or the timer thread could not be spawned.
+    pub fn schedule_after<F>(&self, delay: Duration, f: F) -> ScheduleHandle
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match self.shared.timer.schedule_once(&self.shared, delay, Box::new(f)) {
+            Ok(handle) => handle,
+            Err(e) => panic!("failed to schedule job: {e}"),
+        }
+    }
+
+    /// Run a job on the pool every `period`, starting one period from now, until it is cancelled.
+    ///
+    /// If a run is still queued or running when the next one is due, that run is skipped rather than
+    /// letting runs pile up behind a slow job.
+    ///
+    /// # Panics
+    ///
+    /// The `schedule_every` function will panic if `period` is zero, if the pool is shutting down
+    /// or if the timer thread could not be spawned.
+    pub fn schedule_every<F>(&self, period: Duration, f: F) -> ScheduleHandle
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        assert!(!period.is_zero(), "schedule period must be greater than zero");
+
+        match self.shared.timer.schedule_every(&self.shared, period, Arc::new(f)) {
+            Ok(handle) => handle,
+            Err(e) => panic!("failed to schedule job: {e}"),
+        }
+    }
+
+    /// Stop accepting jobs and wait up to `timeout` for the queued and running jobs to finish.
+    ///
+    /// Jobs scheduled with [`ThreadPool::schedule_after`] or [`ThreadPool::schedule_every`] that aren't due yet
+    /// are discarded. If the timeout runs out, any jobs that haven't started are dropped and workers still in the
+    /// middle of a job are left to finish it in the background. The report says which workers and how many jobs that was.
+    ///
+    /// Once this returns the pool rejects every new job with [`ExecuteError::ShuttingDown`].
+    pub fn shutdown(&self, timeout: Duration) -> ShutdownReport {
+        self.shut_down(Some(Instant::now() + timeout))
+    }
+
+    /// Stop accepting jobs and hand back every job that hasn't started yet, without waiting for anything.
+    ///
+    /// Jobs that are already running carry on, and are waited for when the pool is dropped.
+    /// The returned jobs can be run, stored or dropped as the caller sees fit.
+    pub fn shutdown_now(&self) -> Vec<Job> {
+        self.shared.timer.shutdown();
+        self.shared.queue.close();
+
+        let jobs = self.shared.queue.drain();
+        self.shared.metrics.jobs_dropped(jobs.len());
+        jobs
+    }
+
+    // Shared by Drop, which waits as long as it takes, and shutdown, which gives up at the deadline
+    fn shut_down(&self, deadline: Option<Instant>) -> ShutdownReport {
+        event!(debug, queued = self.shared.queue.len(); "pool shutting down");
+
+        // Stop the timer first so it doesn't try to submit anything once the queue is closed,
+        // and the watchdog so it doesn't start replacement workers while we are shutting them down
+        self.shared.timer.shutdown();
+        self.shared.watchdog.shutdown();
+
+        // We have to close the queue otherwise our threads will loop forever searching for jobs
+        // Workers still finish any jobs that were already queued before they exit
+        self.shared.queue.close();
+
+        // A simulated pool has no workers to finish the queued jobs, so we run them ourselves
+        let finished = if self.shared.queue.is_simulated() {
+            self.shared.run_simulated(deadline)
+        } else {
+            self.shared.wait_for_workers(deadline)
+        };
+
+        // Whatever is still queued at the deadline is never going to be started, so workers are free to exit
+        let dropped_jobs = if finished { 0 } else { self.shared.queue.drain().len() };
+        self.shared.metrics.jobs_dropped(dropped_jobs);
+
+        // A worker that dies while we are joining may still push its replacement, so keep going until the list stays empty.
+        // We take the workers out of the Mutex so the lock isn't held while we wait on them.
+        let mut unfinished_workers = Vec::new();
+        let mut detached = 0;
+        loop {
+            let workers = mem::take(&mut *lock(&self.shared.workers));
+            if workers.is_empty() {
+                break;
+            }
+
+            for worker in workers {
+                // Past the deadline, any worker still running is detached by dropping its JoinHandle.
+                // Idle workers exit as soon as the queue is closed and empty, and a worker that has just taken a job
+                // off the queue may not have started it yet, so joining anything still running could block on a job.
+                if !finished && !worker.thread.is_finished() {
+                    unfinished_workers.push(worker.id);
+                    detached += 1;
+                    continue;
+                }
+
+                // Workers the watchdog replaced no longer count as live, so they are detached too
+                // rather than blocking on a job that may never finish
+                if worker.activity.is_abandoned() && !worker.thread.is_finished() {
+                    unfinished_workers.push(worker.id);
+                    continue;
+                }
+
+                event!(debug, worker = worker.id; "joining worker");
+
+                // join only returns an error if the worker panicked, in which case it is already gone and there is nothing left to clean up
+                let _ = worker.thread.join();
+            }
+        }
+
+        self.shared.detached_workers.fetch_add(detached, Ordering::SeqCst);
+        event!(debug, unfinished_workers = unfinished_workers.len(), dropped_jobs = dropped_jobs; "pool shut down");
+
+        ShutdownReport { unfinished_workers, dropped_jobs }
+    }
+
+    /// Block until every job submitted so far has finished and the queue is empty.
+    ///
+    /// Jobs submitted while we wait are waited for too, including jobs submitted by other jobs.
+    /// Jobs scheduled with [`ThreadPool::schedule_after`] or [`ThreadPool::schedule_every`] only count
+    /// once they are due. Calling this from one of the pool's own jobs never returns, as that job is still running.
+    ///
+    /// With [`Backend::Simulated`] the queued jobs are run on the calling thread until there are none left.
+    pub fn wait_idle(&self) {
+        self.shared.run_if_simulated();
+
+        self.shared.idle.wait(None);
+    }
+
+    /// Like [`ThreadPool::wait_idle`], but gives up after `timeout`.
+    ///
+    /// Returns `true` if the pool went idle, or `false` if the timeout ran out first.
+    pub fn wait_idle_timeout(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+
+        if self.shared.queue.is_simulated() && !self.shared.run_simulated(Some(deadline)) {
+            return false;
+        }
+
+        self.shared.idle.wait(Some(deadline))
+    }
 
-        // send the job down the sending end of the channel for workers to pick up
-        // we have to call unwrap() because if we stopped the receiving threads then send could fail
-        // our threads will continue executing as long as thread pool exists so this is safe
-        self.sender.as_ref().unwrap().send(job).unwrap();
+    /// Run one queued job on the calling thread, for a pool built with [`Backend::Simulated`].
+    ///
+    /// The job is picked at random by the pool's seeded generator. Returns `false` if there was nothing to run.
+    /// A panic in the job is reported like it would be on a worker, with a worker id of 0.
+    ///
+    /// # Panics
+    ///
+    /// The `step` function will panic if the pool wasn't built with [`Backend::Simulated`].
+    pub fn step(&self) -> bool {
+        assert!(self.shared.queue.is_simulated(), "only a pool built with Backend::Simulated can be stepped");
+        self.shared.step()
+    }
+
+    /// Step a pool built with [`Backend::Simulated`] until the queue is empty, including any jobs the jobs submit.
+    /// Returns the number of jobs that were run.
+    ///
+    /// # Panics
+    ///
+    /// The `run_until_idle` function will panic if the pool wasn't built with [`Backend::Simulated`].
+    pub fn run_until_idle(&self) -> usize {
+        assert!(self.shared.queue.is_simulated(), "only a pool built with Backend::Simulated can be stepped");
+
+        let mut ran = 0;
+        while self.shared.step() {
+            ran += 1;
+        }
+        ran
+    }
+
+    /// Take a snapshot of the pool's counters, current load and job timings.
+    pub fn stats(&self) -> PoolStats {
+        self.shared.metrics.snapshot(self.shared.queue.len(), self.shared.live_workers.load(Ordering::SeqCst))
+    }
+
+    /// The number of jobs waiting in the queue for a worker to pick them up.
+    pub fn queue_len(&self) -> usize {
+        self.shared.queue.len()
+    }
+
+    /// The number of worker threads currently running.
+    pub fn worker_count(&self) -> usize {
+        self.shared.live_workers.load(Ordering::SeqCst)
+    }
+
+    /// The core each running worker is pinned to, as `(worker id, core)` pairs sorted by worker id.
+    ///
+    /// Empty unless the pool was built with [`ThreadPoolBuilder::core_affinity`] on Linux.
+    /// A worker that fails to pin itself keeps running unpinned and isn't listed. The failure is only reported
+    /// with the `log` feature enabled.
+    pub fn worker_cores(&self) -> Vec<(usize, usize)> {
+        let workers = lock(&self.shared.workers);
+        let mut cores: Vec<_> = workers
+            .iter()
+            .filter(|worker| !worker.thread.is_finished())
+            .filter_map(|worker| Some((worker.id, *worker.core.get()?)))
+            .collect();
+
+        cores.sort_unstable();
+        cores
     }
-    
 }
 
 // When the pool is dropped we want all the threads to finish their work
 impl Drop for ThreadPool {
     fn drop(&mut self) {
+        self.shut_down(None);
+    }
+}
+
+impl Shared {
+    // Blocks until every worker has exited or the deadline passes. Returns false if we gave up at the deadline.
+    fn wait_for_workers(&self, deadline: Option<Instant>) -> bool {
+        let mut workers = lock(&self.workers);
+
+        while self.live_workers.load(Ordering::SeqCst) > self.detached_workers.load(Ordering::SeqCst) {
+            workers = match deadline {
+                None => self.worker_exited.wait(workers).unwrap_or_else(PoisonError::into_inner),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+
+                    self.worker_exited
+                        .wait_timeout(workers, deadline - now)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+            };
+        }
+
+        true
+    }
 
-        // We have to drop the sender to close the channel otherwise our threads will loop forever searching for jobs
-        drop(self.sender.take());
+    // Blocks until every worker spawned so far has got through its start hook or died trying.
+    // Returns false if any worker has died before starting.
+    fn wait_for_startup(&self) -> bool {
+        let mut workers = lock(&self.workers);
 
-        // we use &mut here because self is a mutable reference and we need to mutate the worker.
-        for worker in &mut self.workers {
-            println!("Shutting down worker {}", worker.id);
+        while self.starting_workers.load(Ordering::SeqCst) > 0 {
+            workers = self.worker_exited.wait(workers).unwrap_or_else(PoisonError::into_inner);
+        }
+
+        self.failed_starts.load(Ordering::SeqCst) == 0
+    }
+
+    // Claims a slot for a new worker, unless the pool is already running max_threads
+    fn reserve_worker(&self) -> bool {
+        self.live_workers
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| (live < self.max_threads).then_some(live + 1))
+            .is_ok()
+    }
+
+    // Gives up a worker's slot, unless that would take the pool below min_threads
+    fn retire_worker(&self) -> bool {
+        self.live_workers
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| (live > self.min_threads).then_some(live - 1))
+            .is_ok()
+    }
 
-            // the `take` method on Option takes the Some variant out and leaves None in its place
-            if let Some(thread) = worker.thread.take() {
-                // join takes ownership of it's argument
-                thread.join().unwrap();
+    // Starts another worker if the pool is below max_threads. Returns Ok(false) if it is already full.
+    fn add_worker(self: &Arc<Self>) -> io::Result<bool> {
+        // A simulated pool runs its jobs on whichever thread steps it, never on workers
+        if self.queue.is_simulated() || !self.reserve_worker() {
+            return Ok(false);
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        Worker::spawn(id, Arc::clone(self))?;
+
+        Ok(true)
+    }
+
+    // Everything that submits jobs goes through here: the pool itself, and the timer thread
+    fn try_execute<F>(self: &Arc<Self>, priority: Priority, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.try_execute_with(priority, JobInfo::default(), f)
+    }
+
+    // Like try_execute, for jobs submitted with a cancellation token, label or time budget
+    fn try_execute_with<F>(self: &Arc<Self>, priority: Priority, info: JobInfo, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: Runnable,
+    {
+        let result = self.submit(priority, info, f);
+
+        if result.is_err() {
+            self.metrics.job_rejected();
+        }
+
+        result
+    }
+
+    // For jobs the pool submits itself to carry on work it has already accepted: the next job for a key, the dependents
+    // of a graph node that finished, a future that was woken. These are often submitted from a worker, so they never
+    // wait for room or run inline, which could deadlock a full queue or recurse without end. They go on the queue past
+    // its capacity, and are only rejected once the pool is shutting down.
+    fn requeue<F>(self: &Arc<Self>, mut info: JobInfo, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        info.past_capacity = true;
+        self.try_execute_with(Priority::NORMAL, info, f)
+    }
+
+    // Shared by ThreadPool::spawn and PoolHandle::spawn
+    fn spawn<F, T>(self: &Arc<Self>, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (completer, handle) = handle::pair();
+
+        // If the job is rejected the closure is dropped along with the completer, which marks the handle as cancelled
+        let _ = self.try_execute(Priority::NORMAL, move || {
+            completer.complete(panic::catch_unwind(AssertUnwindSafe(f)));
+        });
+
+        handle
+    }
+
+    fn submit<F>(self: &Arc<Self>, priority: Priority, mut info: JobInfo, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: Runnable,
+    {
+        if self.queue.is_closed() {
+            return Err(ExecuteError::ShuttingDown(f));
+        }
+
+        // A job whose token is already cancelled would only be removed again, so it is dropped here instead
+        if let Some(token) = &info.token {
+            if !token.register_pool(self) {
+                return Ok(());
             }
+        }
 
+        // There may be no workers yet if min_threads is zero, or they may all have died without being replaced.
+        // Either way we try to start one, and if we can't then nothing would ever pick the job up.
+        if self.live_workers.load(Ordering::SeqCst) == 0 && !self.queue.is_simulated() && !matches!(self.add_worker(), Ok(true)) {
+            return Err(ExecuteError::NoWorkers(f));
+        }
+
+        // If the push fails the info is dropped along with this, so the job never counts as pending
+        info.pending = Some(self.idle.track());
+
+        // put the job on the queue for workers to pick up
+        match self.queue.push(f, priority, info) {
+            Ok(dropped) => {
+                self.metrics.job_submitted();
+
+                // Dropping a job can run arbitrary destructors, which is why the queue hands it to us
+                // to drop outside its lock
+                if let Some(dropped) = dropped {
+                    self.metrics.jobs_dropped(1);
+                    drop(dropped);
+                }
+
+                // If jobs are piling up faster than the idle workers can take them, start another worker.
+                // Failing to start one is fine, the job is still queued for the workers we already have.
+                if self.queue.is_backed_up() {
+                    let _ = self.add_worker();
+                }
+
+                Ok(())
+            }
+            Err(PushError::Closed(f)) => Err(ExecuteError::ShuttingDown(f)),
+            Err(PushError::Full(f)) => {
+                if self.queue.policy() == OverflowPolicy::CallerRuns {
+                    f.run();
+                    Ok(())
+                } else {
+                    Err(ExecuteError::QueueFull(f))
+                }
+            }
+        }
+    }
+
+    // Runs the next job of a simulated pool on the calling thread, the way a worker would. Returns false if there wasn't one.
+    fn step(&self) -> bool {
+        let queued = loop {
+            match self.queue.next_simulated() {
+                Some(queued) if queued.is_cancelled() => self.metrics.jobs_dropped(1),
+                Some(queued) => break queued,
+                None => return false,
+            }
+        };
+
+        let Queued { job, enqueued, info } = queued;
+        event!(trace, queued = self.queue.len(); "simulated job started");
+        self.metrics.job_started(enqueued.elapsed());
+        let started = Instant::now();
+
+        let result = panic::catch_unwind(AssertUnwindSafe(job));
+
+        self.metrics.job_finished(started.elapsed(), result.is_err());
+        if let Err(payload) = result {
+            self.report_panic(0, payload);
+        }
+
+        // The job only stops counting as pending once it is completely done
+        drop(info);
+        true
+    }
+
+    // Steps a simulated pool until the queue is empty or the deadline passes. Returns false if we gave up at the deadline.
+    fn run_simulated(&self, deadline: Option<Instant>) -> bool {
+        while self.queue.len() > 0 {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return false;
+            }
+            self.step();
+        }
+
+        true
+    }
+
+    // Called before blocking until jobs finish. A simulated pool only runs jobs when it is stepped, so they would
+    // never finish if we just waited: run them on the calling thread instead. Does nothing for a threaded pool.
+    fn run_if_simulated(&self) {
+        if self.queue.is_simulated() {
+            self.run_simulated(None);
+        }
+    }
+
+    fn report_panic(&self, id: usize, payload: Box<dyn Any + Send>) {
+        match &self.panic_handler {
+            Some(handler) => handler(id, payload),
+            None => event!(error, worker = id; "job panicked"),
         }
     }
 }
 
 struct Worker {
     id: usize,
-    thread: Option<thread::JoinHandle<()>>,
+    thread: thread::JoinHandle<()>,
+    // What the worker is running, for shutting down and for the watchdog
+    activity: Arc<Activity>,
+    // The core the worker pinned itself to, set by the worker once pinning has succeeded
+    core: Arc<OnceLock<usize>>,
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            // we call lock() to acquire the mutex
-            // then call unwrap() to panic on errors. Note this may fail if mutex was acquired in a poisoned state
-            // which can happen if another thread panicked whilst holding the lock rather than releasing the lock.
-
-            // We unwrap() after recv() to panic if the sender closed down and thus we couldn't receive the job.
-            let message = receiver.lock().unwrap().recv();
-            match message {
-                Ok(job) => {
-                    println!("Worker {id} got a job; executing.");
-
-                    job();
+    // Starts a worker thread and registers it with the pool. The caller must already have reserved a slot in live_workers.
+    // We use thread::Builder rather than thread::spawn so that a failure to create the thread
+    // is returned to us as an io::Error instead of panicking.
+    fn spawn(id: usize, shared: Arc<Shared>) -> io::Result<()> {
+        // The guard owns the worker's slot in live_workers.
+        // If spawning fails the closure is dropped along with the guard, which gives the slot back.
+        shared.starting_workers.fetch_add(1, Ordering::SeqCst);
+        let guard = WorkerGuard { id, shared: Arc::clone(&shared), started: false, retired: false };
+        let activity = Arc::new(Activity::new());
+        let thread_activity = Arc::clone(&activity);
+        let core = shared.pinned_cores.as_ref().map(|cores| cores[id % cores.len()]);
+        let pinned = Arc::new(OnceLock::new());
+        let thread_pinned = Arc::clone(&pinned);
+
+        let mut builder = thread::Builder::new();
+        if let Some(namer) = &shared.thread_name {
+            builder = builder.name(namer(id));
+        }
+        if let Some(stack_size) = shared.stack_size {
+            builder = builder.stack_size(stack_size);
+        }
+
+        let thread = builder.spawn(move || {
+            // Moving the guard into the thread ties it to the thread's lifetime, including when it panics
+            let mut guard = guard;
+            let shared = Arc::clone(&guard.shared);
+
+            // Pin before anything else runs so the start hook already sees the thread on its core
+            if let Some(core) = core {
+                match affinity::pin_current_thread(core) {
+                    Ok(()) => {
+                        let _ = thread_pinned.set(core);
+                    }
+                    Err(e) => event!(warn, worker = id, core = core; "worker couldn't be pinned to core {core}: {e}"),
+                }
+            }
+
+            event!(debug, worker = id; "worker started");
+
+            if let Some(hook) = &shared.on_thread_start {
+                hook(id);
+            }
+            guard.started();
+
+            // Declared after the start hook runs, so the stop hook only runs if the start hook did
+            let _stop_hook = StopHook { id, hook: shared.on_thread_stop.clone() };
+
+            // With the work-stealing backend this gives the worker its own deque until the thread exits
+            let _registration = shared.queue.register_worker();
+
+            loop {
+                // Workers above the minimum only wait keep_alive for a job before retiring
+                let timeout = (shared.live_workers.load(Ordering::SeqCst) > shared.min_threads).then_some(shared.keep_alive);
+
+                // pop() blocks until there is a job to run, and only returns Closed once the pool
+                // has closed the queue and every job left in it has been handed out.
+                match shared.queue.pop(timeout) {
+                    // The token may have been cancelled after we took the job but before the queue was cleared
+                    Pop::Job(queued) if queued.is_cancelled() => shared.metrics.jobs_dropped(1),
+                    Pop::Job(queued) => {
+                        event!(trace, worker = id; "job started");
+                        thread_activity.start(&queued.info);
+                        shared.metrics.job_started(queued.enqueued.elapsed());
+                        let started = Instant::now();
+
+                        // Catch the panic so one bad job doesn't take the worker down with it.
+                        // AssertUnwindSafe is fine because the job is gone afterwards and nothing it touched is reused.
+                        let result = panic::catch_unwind(AssertUnwindSafe(queued.job));
+
+                        let took = started.elapsed();
+                        shared.metrics.job_finished(took, result.is_err());
+                        event!(trace, worker = id, took_us = took.as_micros() as u64, panicked = result.is_err(); "job finished");
+
+                        // If the watchdog replaced us while the job was running, our slot already belongs to the replacement
+                        let abandoned = thread_activity.finish();
+                        guard.retired = abandoned;
+
+                        if let Err(payload) = result {
+                            shared.report_panic(id, payload);
+                        }
+
+                        if abandoned {
+                            event!(debug, worker = id; "worker finished its overdue job and exited");
+                            break;
+                        }
+                    }
+                    Pop::TimedOut => {
+                        // Another worker may have retired first and taken us down to min_threads, in which case we stay
+                        if shared.retire_worker() {
+                            guard.retired = true;
+                            event!(debug, worker = id; "worker retired after being idle");
+                            break;
+                        }
+                    }
+                    Pop::Closed => {
+                        event!(debug, worker = id; "worker stopped as the pool is shutting down");
+                        break;
+                    }
                 }
-                Err(_) => {
-                    println!("Worker {id} disconnected; shutting down.");
-                    break;
+            }
+        })?;
+
+        // Threads that already exited (retired, or died and were replaced) are joined now so their handles don't pile up
+        let mut workers = lock(&shared.workers);
+        for worker in workers.extract_if(.., |worker| worker.thread.is_finished()) {
+            let _ = worker.thread.join();
+        }
+        workers.push(Worker { id, thread, activity, core: pinned });
+
+        Ok(())
+    }
+}
+
+// Runs the on_thread_stop hook when the worker thread exits, however it exits
+struct StopHook {
+    id: usize,
+    hook: Option<Arc<ThreadHook>>,
+}
+
+impl Drop for StopHook {
+    fn drop(&mut self) {
+        if let Some(hook) = &self.hook {
+            hook(self.id);
+        }
+    }
+}
+
+// Keeps `live_workers` in sync with the number of running worker threads, and acts as the worker's supervisor:
+// if the thread dies from a panic that escaped the job (e.g. in the panic handler), a replacement is started
+// so the pool keeps its size.
+struct WorkerGuard {
+    id: usize,
+    shared: Arc<Shared>,
+    // Set once the worker has got through its start hook. A worker that dies before then isn't replaced,
+    // as the replacement would most likely die in the same place, and so on without end.
+    started: bool,
+    // Set once the worker has already given up its slot by retiring
+    retired: bool,
+}
+
+impl WorkerGuard {
+    fn started(&mut self) {
+        self.started = true;
+        self.shared.starting_workers.fetch_sub(1, Ordering::SeqCst);
+
+        // Taking the lock makes sure a build that just checked starting_workers is already waiting to hear this
+        let _workers = lock(&self.shared.workers);
+        self.shared.worker_exited.notify_all();
+    }
+}
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        if !self.started {
+            self.shared.starting_workers.fetch_sub(1, Ordering::SeqCst);
+
+            // Also reached when the thread couldn't be spawned at all, which isn't a panic
+            if thread::panicking() {
+                self.shared.failed_starts.fetch_add(1, Ordering::SeqCst);
+                event!(error, worker = self.id; "worker panicked before it started taking jobs; not replacing it");
+            }
+        }
+
+        if !self.retired {
+            self.shared.live_workers.fetch_sub(1, Ordering::SeqCst);
+
+            if self.started && thread::panicking() && !self.shared.queue.is_closed() && self.shared.reserve_worker() {
+                let id = self.id;
+                event!(warn, worker = id; "worker died; starting a replacement");
+
+                if let Err(e) = Worker::spawn(id, Arc::clone(&self.shared)) {
+                    event!(error, worker = id; "failed to replace worker: {e}");
                 }
             }
+        }
+
+        // Taking the lock makes sure a shutdown that just checked live_workers is already waiting to hear this
+        let _workers = lock(&self.shared.workers);
+        self.shared.worker_exited.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            mpsc, Arc, Mutex,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::{lock, CancellationToken, CoreAffinity, ExecuteError, OverflowPolicy, PoolCreationError, ThreadPool};
+
+    #[test]
+    fn panicking_job_is_reported_and_the_worker_keeps_going() {
+        let (reported, reports) = mpsc::channel();
+        let pool = ThreadPool::builder()
+            .num_threads(2)
+            .panic_handler(move |_, payload| {
+                let message = payload.downcast_ref::<&str>().copied().unwrap_or_default();
+                reported.send(message).unwrap();
+            })
+            .build()
+            .unwrap();
+
+        pool.execute(|| panic!("job failed"));
+        assert_eq!(reports.recv_timeout(Duration::from_secs(5)), Ok("job failed"));
+
+        let ran = Arc::new(AtomicUsize::new(0));
+        for _ in 0..10 {
+            let ran = Arc::clone(&ran);
+            pool.execute(move || {
+                ran.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        assert!(pool.wait_idle_timeout(Duration::from_secs(5)));
+        assert_eq!(ran.load(Ordering::SeqCst), 10);
+        assert_eq!(pool.worker_count(), 2);
+    }
+
+    #[test]
+    fn dead_worker_is_replaced() {
+        let starts = Arc::new(AtomicUsize::new(0));
+        let hook_starts = Arc::clone(&starts);
+        let failed = AtomicBool::new(false);
+
+        // A panicking panic handler is the one way left for a job to take its worker down
+        let pool = ThreadPool::builder()
+            .num_threads(2)
+            .on_thread_start(move |_| {
+                hook_starts.fetch_add(1, Ordering::SeqCst);
+            })
+            .panic_handler(move |_, _| {
+                if !failed.swap(true, Ordering::SeqCst) {
+                    panic!("panic handler failed");
+                }
+            })
+            .build()
+            .unwrap();
+
+        pool.execute(|| panic!("job failed"));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while starts.load(Ordering::SeqCst) < 3 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(starts.load(Ordering::SeqCst), 3);
+        assert_eq!(pool.worker_count(), 2);
+
+        let (done, finished) = mpsc::channel();
+        pool.execute(move || done.send(()).unwrap());
+        assert!(finished.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+
+    #[test]
+    fn lock_recovers_from_poisoning() {
+        let mutex = Arc::new(Mutex::new(0));
+        let poisoner = Arc::clone(&mutex);
+
+        let _ = thread::spawn(move || {
+            let _guard = poisoner.lock().unwrap();
+            panic!("poisoning the lock");
+        })
+        .join();
+
+        assert!(mutex.is_poisoned());
+        *lock(&mutex) += 1;
+        assert_eq!(*lock(&mutex), 1);
+    }
+
+    #[test]
+    fn panicking_start_hook_fails_the_build_without_respawning() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let hook_calls = Arc::clone(&calls);
+
+        let result = ThreadPool::builder()
+            .num_threads(2)
+            .on_thread_start(move |_| {
+                hook_calls.fetch_add(1, Ordering::SeqCst);
+                panic!("start hook failed");
+            })
+            .build();
+
+        assert!(matches!(result, Err(PoolCreationError::WorkerStartPanicked)));
+
+        // Give any replacement a chance to show up
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn shutdown_detaches_workers_still_running_at_the_deadline() {
+        let pool = ThreadPool::new(2);
+        let (release, blocked) = mpsc::channel::<()>();
+        let (started, running) = mpsc::channel();
+
+        pool.execute(move || {
+            started.send(()).unwrap();
+            let _ = blocked.recv();
         });
+        running.recv().unwrap();
+
+        let start = Instant::now();
+        let report = pool.shutdown(Duration::from_millis(50));
+
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(report.unfinished_workers.len(), 1);
+        assert!(!report.is_complete());
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn try_execute_cancellable_hands_the_closure_back() {
+        let pool = ThreadPool::new(1);
+        pool.shutdown(Duration::from_secs(1));
 
-        Worker { id, thread: Some(thread) }
+        let ran = Arc::new(AtomicUsize::new(0));
+        let counted = |ran: &Arc<AtomicUsize>| {
+            let ran = Arc::clone(ran);
+            move |_: &CancellationToken| {
+                ran.fetch_add(1, Ordering::SeqCst);
+            }
+        };
+
+        match pool.try_execute_cancellable(counted(&ran)) {
+            Err(ExecuteError::ShuttingDown(f)) => f(&CancellationToken::new()),
+            other => panic!("expected ShuttingDown, got {other:?}"),
+        }
+
+        let token = CancellationToken::new();
+        pool.try_execute_with_token(&token, counted(&ran)).unwrap_err().into_inner()(&token);
+
+        assert_eq!(ran.load(Ordering::SeqCst), 2);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn try_execute_labeled_and_with_budget_hand_the_closure_back() {
+        let pool = ThreadPool::new(1);
+        pool.shutdown(Duration::from_secs(1));
+
+        let ran = Arc::new(AtomicUsize::new(0));
+        let counted = |ran: &Arc<AtomicUsize>| {
+            let ran = Arc::clone(ran);
+            move || {
+                ran.fetch_add(1, Ordering::SeqCst);
+            }
+        };
+
+        match pool.try_execute_labeled("labeled", counted(&ran)) {
+            Err(ExecuteError::ShuttingDown(f)) => f(),
+            other => panic!("expected ShuttingDown, got {other:?}"),
+        }
+        pool.try_execute_with_budget("budget", Duration::from_secs(1), counted(&ran)).unwrap_err().into_inner()();
+
+        assert_eq!(ran.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn worker_cores_lists_the_workers_that_were_pinned() {
+        let pool = ThreadPool::builder().num_threads(2).core_affinity(CoreAffinity::RoundRobin).build().unwrap();
+        let cores = pool.worker_cores();
+
+        // Workers only show up once they have actually pinned themselves, which is never off Linux
+        if cfg!(target_os = "linux") {
+            assert_eq!(cores.iter().map(|&(id, _)| id).collect::<Vec<_>>(), [0, 1]);
+        } else {
+            assert!(cores.is_empty());
+        }
+    }
+
+    #[test]
+    fn stats_account_for_every_submitted_job() {
+        let pool = ThreadPool::builder()
+            .num_threads(1)
+            .queue_capacity(2)
+            .overflow_policy(OverflowPolicy::DropOldest)
+            .panic_handler(|_, _| {})
+            .build()
+            .unwrap();
+        let (started, running) = mpsc::channel();
+
+        let block = |started: &mpsc::Sender<()>| {
+            let started = started.clone();
+            let (release, blocked) = mpsc::channel::<()>();
+            let job = move || {
+                started.send(()).unwrap();
+                let _ = blocked.recv();
+            };
+            (release, job)
+        };
+
+        let (release, job) = block(&started);
+        pool.execute(job);
+        running.recv().unwrap();
+
+        // The third job drops the first to make room, the cancellable one drops the second and is then cancelled
+        for _ in 0..3 {
+            pool.execute(|| {});
+        }
+        let token = CancellationToken::new();
+        pool.execute_with_token(&token, |_| {});
+        token.cancel();
+        pool.execute(|| panic!("job panicked on purpose"));
+
+        release.send(()).unwrap();
+        assert!(pool.wait_idle_timeout(Duration::from_secs(5)));
+
+        let stats = pool.stats();
+        assert_eq!((stats.submitted, stats.completed, stats.panicked, stats.dropped), (6, 2, 1, 3));
+
+        // A job still queued at the shutdown deadline is dropped too
+        let (release, job) = block(&started);
+        pool.execute(job);
+        running.recv().unwrap();
+        pool.execute(|| {});
+        assert_eq!(pool.shutdown(Duration::from_millis(50)).dropped_jobs, 1);
+        release.send(()).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.stats().completed < 3 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let stats = pool.stats();
+        assert_eq!((stats.submitted, stats.completed, stats.panicked, stats.dropped), (8, 3, 1, 4));
+        assert_eq!(stats.rejected, 0);
+        assert_eq!(stats.queued, 0);
+    }
+
+    #[test]
+    fn pool_grows_to_max_threads_and_shrinks_after_keep_alive() {
+        let pool = ThreadPool::builder()
+            .min_threads(1)
+            .max_threads(4)
+            .keep_alive(Duration::from_millis(50))
+            .build()
+            .unwrap();
+        assert_eq!(pool.worker_count(), 1);
+
+        let (started, running) = mpsc::channel();
+        let (release, blocked) = mpsc::channel::<()>();
+        let blocked = Arc::new(Mutex::new(blocked));
+
+        // More jobs than max_threads, all of which block, so the queue backs up
+        for _ in 0..6 {
+            let (started, blocked) = (started.clone(), Arc::clone(&blocked));
+            pool.execute(move || {
+                started.send(()).unwrap();
+                let _ = blocked.lock().unwrap().recv();
+            });
+        }
+
+        for _ in 0..4 {
+            running.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        assert_eq!(pool.worker_count(), 4);
+
+        for _ in 0..6 {
+            release.send(()).unwrap();
+        }
+        assert!(pool.wait_idle_timeout(Duration::from_secs(5)));
+
+        // The extra workers retire once they have been idle for keep_alive, down to min_threads
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.worker_count() > 1 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(pool.worker_count(), 1);
+    }
+
+    #[test]
+    fn wait_idle_waits_for_jobs_submitted_by_other_jobs() {
+        let pool = ThreadPool::new(2);
+        let handle = pool.handle();
+        let ran = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..10 {
+            let (handle, ran) = (handle.clone(), Arc::clone(&ran));
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(1));
+                handle.execute(move || {
+                    thread::sleep(Duration::from_millis(1));
+                    ran.fetch_add(1, Ordering::SeqCst);
+                });
+            });
+        }
+
+        pool.wait_idle();
+        assert_eq!(ran.load(Ordering::SeqCst), 10);
+
+        // The pool can be used again for another round once it is idle
+        let inner = Arc::clone(&ran);
+        pool.execute(move || {
+            inner.fetch_add(1, Ordering::SeqCst);
+        });
+        pool.wait_idle();
+        assert_eq!(ran.load(Ordering::SeqCst), 11);
+    }
+
+    #[test]
+    fn wait_idle_timeout_gives_up_while_a_job_is_running() {
+        let pool = ThreadPool::new(1);
+        let (release, blocked) = mpsc::channel::<()>();
+
+        pool.execute(move || {
+            let _ = blocked.recv();
+        });
+
+        let start = Instant::now();
+        assert!(!pool.wait_idle_timeout(Duration::from_millis(30)));
+        assert!(start.elapsed() >= Duration::from_millis(30));
+
+        release.send(()).unwrap();
+        assert!(pool.wait_idle_timeout(Duration::from_secs(5)));
+    }
+}
@@ -0,0 +1,19 @@
+// The pool reports what it is doing through the `log` crate when the `log` feature is enabled, and stays quiet otherwise.
+//
+// Events carry their details as key-values, e.g. `event!(debug, worker = id; "worker started")`, so a structured
+// logger can pick them out without parsing the message. Without the feature the arguments are still evaluated
+// (they are all cheap) so that nothing only used for logging ends up unused.
+macro_rules! event {
+    ($level:ident, $($key:ident = $value:expr),* ; $($message:tt)+) => {{
+        #[cfg(feature = "log")]
+        ::log::$level!($($key = $value),* ; $($message)+);
+
+        #[cfg(not(feature = "log"))]
+        {
+            $(let _ = &$value;)*
+            let _ = format_args!($($message)+);
+        }
+    }};
+}
+
+pub(crate) use event;
@@ -1,29 +1,42 @@
 use std::{
     fs,
     io::{prelude::*, BufReader},
-    net::{TcpListener, TcpStream}, thread, time::Duration,
+    net::{TcpListener, TcpStream}, sync::Arc, thread, time::Duration,
 };
 
-use hello::ThreadPool;
+use hello::{OverflowPolicy, ThreadPool};
 
 fn main() {
     let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
-    let pool = ThreadPool::new(4);
+
+    // Bound the queue so a flood of connections can't grow memory without limit,
+    // anything that doesn't fit gets a 503 instead.
+    // The builder starts one thread per CPU we're allowed to use, so we don't pick a number here.
+    let pool = ThreadPool::builder()
+        .queue_capacity(64)
+        .overflow_policy(OverflowPolicy::Reject)
+        .build()
+        .unwrap();
 
     // take 2 requests then shutdown
     // it will shudown because end of main() is reached, meaning the pool goes out of scope and the `drop` implementation will run
     for stream in listener.incoming().take(2) {
-        let stream = stream.unwrap();
+        let stream = Arc::new(stream.unwrap());
+        let job_stream = Arc::clone(&stream);
 
-        pool.execute(|| {
-            handle_connection(stream);
-        });
+        // if the pool refuses the job we still hold on to the stream, so we can tell the client to try again later
+        if let Err(e) = pool.try_execute(move || {
+            handle_connection(&job_stream);
+        }) {
+            println!("Rejecting connection: {e}");
+            respond_unavailable(&stream);
+        }
     }
 
     println!("Shutting down server...");
 }
 
-fn handle_connection(mut stream: TcpStream) {
+fn handle_connection(mut stream: &TcpStream) {
     let buf_reader = BufReader::new(&mut stream);
 
     // HTTP Responses have this format:
@@ -53,4 +66,11 @@ fn handle_connection(mut stream: TcpStream) {
 
     stream.write_all(response.as_bytes()).unwrap();
 
-}
\ No newline at end of file
+}
+
+fn respond_unavailable(mut stream: &TcpStream) {
+    let response = "HTTP/1.1 503 SERVICE UNAVAILABLE\r\nContent-Length: 0\r\n\r\n";
+
+    // the client may already have gone away, in which case there is nobody left to tell
+    let _ = stream.write_all(response.as_bytes());
+}
@@ -0,0 +1,147 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+};
+
+use crate::ThreadPool;
+
+// How many chunks per worker `map` and `for_each` split their items into when no chunk size is given.
+// More than one, so a worker that gets slow items doesn't hold everyone else up at the end.
+const CHUNKS_PER_WORKER: usize = 4;
+
+// Set when a chunk panics, so the chunks still running stop early instead of finishing work that gets thrown away
+struct FailOnPanic<'a>(&'a AtomicBool);
+
+impl Drop for FailOnPanic<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+pub(crate) fn default_chunk_size(pool: &ThreadPool, len: usize) -> usize {
+    len.div_ceil(pool.shared.max_threads.saturating_mul(CHUNKS_PER_WORKER)).max(1)
+}
+
+// Runs `f` over the items in chunks of `chunk_size`, one job per chunk, and returns the results in the same order.
+// Built on a scope, so neither the items nor `f` have to be 'static.
+pub(crate) fn map_chunked<I, F, T>(pool: &ThreadPool, items: I, chunk_size: usize, f: F) -> Vec<T>
+where
+    I: IntoIterator,
+    I::Item: Send,
+    F: Fn(I::Item) -> T + Sync,
+    T: Send,
+{
+    assert!(chunk_size > 0, "chunk size must be greater than zero");
+
+    let mut items = items.into_iter();
+    let mut chunks = Vec::new();
+    loop {
+        let chunk: Vec<_> = items.by_ref().take(chunk_size).collect();
+        if chunk.is_empty() {
+            break;
+        }
+        chunks.push(chunk);
+    }
+
+    // Each job fills in its own slice of the results, which keeps them in order without any locking
+    let mut results: Vec<Option<T>> = Vec::new();
+    results.resize_with(chunks.iter().map(Vec::len).sum(), || None);
+
+    let failed = AtomicBool::new(false);
+    let f = &f;
+
+    pool.scope(|s| {
+        for (chunk, slots) in chunks.into_iter().zip(results.chunks_mut(chunk_size)) {
+            let failed = &failed;
+
+            s.execute(move || {
+                let _fail_on_panic = FailOnPanic(failed);
+
+                for (item, slot) in chunk.into_iter().zip(slots) {
+                    if failed.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    *slot = Some(f(item));
+                }
+            });
+        }
+    });
+
+    // The scope re-raises a panic from any chunk, so if we got here every chunk ran to the end
+    results.into_iter().map(|result| result.expect("every chunk finished")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        panic::{self, AssertUnwindSafe},
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    use crate::ThreadPool;
+
+    #[test]
+    fn map_keeps_the_input_order() {
+        let pool = ThreadPool::new(4);
+
+        // Uneven work so the chunks finish out of order
+        let doubled = pool.map(0..1000, |i: u64| {
+            if i.is_multiple_of(97) {
+                thread::sleep(Duration::from_millis(1));
+            }
+            i * 2
+        });
+
+        assert_eq!(doubled, (0..1000).map(|i| i * 2).collect::<Vec<_>>());
+        assert!(pool.map(Vec::<u64>::new(), |i| i).is_empty());
+    }
+
+    #[test]
+    fn chunked_variants_cover_every_item_once() {
+        let pool = ThreadPool::new(4);
+
+        // 10 items in chunks of 3 leaves a short chunk at the end
+        assert_eq!(pool.map_chunked(0..10, 3, |i| i + 1), (1..11).collect::<Vec<_>>());
+
+        let seen: Vec<_> = (0..10).map(|_| AtomicUsize::new(0)).collect();
+        pool.for_each_chunked(0..10, 3, |i| {
+            seen[i].fetch_add(1, Ordering::SeqCst);
+        });
+        assert!(seen.iter().all(|count| count.load(Ordering::SeqCst) == 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "chunk size must be greater than zero")]
+    fn map_chunked_panics_on_a_zero_chunk_size() {
+        ThreadPool::new(1).map_chunked(0..10, 0, |i| i);
+    }
+
+    #[test]
+    #[should_panic(expected = "chunk size must be greater than zero")]
+    fn for_each_chunked_panics_on_a_zero_chunk_size() {
+        ThreadPool::new(1).for_each_chunked(0..10, 0, |_| {});
+    }
+
+    #[test]
+    fn panic_from_f_reaches_the_caller() {
+        let pool = ThreadPool::new(4);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.for_each(0..100, |i| {
+                if i == 42 {
+                    panic!("item 42 failed");
+                }
+            })
+        }));
+
+        let payload = result.unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"item 42 failed"));
+
+        // The pool is still usable afterwards
+        assert_eq!(pool.map(0..3, |i| i), [0, 1, 2]);
+    }
+}
@@ -0,0 +1,167 @@
+use std::{fmt, sync::Arc};
+
+use crate::{ExecuteError, JobHandle, Priority, Shared};
+
+/// A cheap, cloneable handle for submitting jobs to a [`ThreadPool`](crate::ThreadPool) from any thread,
+/// created by `ThreadPool::handle`.
+///
+/// Handles can be sent to other threads and into the pool's own jobs, and don't need the pool to be shared.
+/// They don't keep the pool running though: the `ThreadPool` still decides when the pool shuts down.
+/// Once it has started shutting down, whether from `ThreadPool::shutdown`, `ThreadPool::shutdown_now` or being
+/// dropped, every handle's jobs are rejected with [`ExecuteError::ShuttingDown`]. Jobs a handle submitted before
+/// that are treated like any other queued job, so dropping the pool still waits for them.
+#[derive(Clone)]
+pub struct PoolHandle {
+    shared: Arc<Shared>,
+}
+
+impl PoolHandle {
+    pub(crate) fn new(shared: Arc<Shared>) -> PoolHandle {
+        PoolHandle { shared }
+    }
+
+    /// Execute a job on one of the pool's threads.
+    ///
+    /// # Panics
+    ///
+    /// The `execute` function will panic if the job is rejected, including when the pool is shutting down.
+    /// See [`PoolHandle::try_execute`].
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Err(e) = self.try_execute(f) {
+            panic!("failed to execute job: {e}");
+        }
+    }
+
+    /// Execute a job on one of the pool's threads, handing the closure back if the pool can't run it.
+    ///
+    /// This behaves like `ThreadPool::try_execute`, and returns [`ExecuteError::ShuttingDown`] once the pool
+    /// has started shutting down.
+    pub fn try_execute<F>(&self, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.shared.try_execute(Priority::NORMAL, f)
+    }
+
+    /// Execute a job with the given [`Priority`].
+    ///
+    /// # Panics
+    ///
+    /// The `execute_with_priority` function will panic if the job is rejected, see [`PoolHandle::try_execute`].
+    pub fn execute_with_priority<F>(&self, priority: Priority, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Err(e) = self.try_execute_with_priority(priority, f) {
+            panic!("failed to execute job: {e}");
+        }
+    }
+
+    /// Execute a job with the given [`Priority`], handing the closure back if the pool can't run it.
+    pub fn try_execute_with_priority<F>(&self, priority: Priority, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.shared.try_execute(priority, f)
+    }
+
+    /// Run a job on the pool and get a handle that can be used to wait for its return value, like `ThreadPool::spawn`.
+    ///
+    /// If the pool is shutting down, joining the returned handle gives [`JoinError::Cancelled`](crate::JoinError::Cancelled).
+    pub fn spawn<F, T>(&self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.shared.spawn(f)
+    }
+
+    /// Returns `true` once the pool has started shutting down, after which every job is rejected.
+    pub fn is_shut_down(&self) -> bool {
+        self.shared.queue.is_closed()
+    }
+}
+
+impl fmt::Debug for PoolHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PoolHandle")
+            .field("shut_down", &self.is_shut_down())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc, Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    use crate::{ExecuteError, JoinError, ThreadPool};
+
+    #[test]
+    fn handles_submit_from_other_threads_and_from_jobs() {
+        let pool = ThreadPool::new(2);
+        let ran = Arc::new(AtomicUsize::new(0));
+
+        let producers: Vec<_> = (0..4)
+            .map(|_| {
+                let (handle, ran) = (pool.handle(), Arc::clone(&ran));
+                thread::spawn(move || {
+                    let inner = handle.clone();
+                    handle.execute(move || {
+                        inner.execute(move || {
+                            ran.fetch_add(1, Ordering::SeqCst);
+                        });
+                    });
+                })
+            })
+            .collect();
+
+        for producer in producers {
+            producer.join().unwrap();
+        }
+
+        pool.wait_idle();
+        assert_eq!(ran.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn handle_is_rejected_once_the_pool_shuts_down() {
+        let pool = ThreadPool::new(1);
+        let handle = pool.handle();
+        assert!(!handle.is_shut_down());
+
+        pool.shutdown(Duration::from_secs(1));
+        assert!(handle.is_shut_down());
+
+        assert!(matches!(handle.try_execute(|| {}), Err(ExecuteError::ShuttingDown(_))));
+        assert!(matches!(handle.spawn(|| 1).join(), Err(JoinError::Cancelled)));
+
+        // Outliving the pool is fine too
+        drop(pool);
+        assert!(matches!(handle.try_execute(|| {}), Err(ExecuteError::ShuttingDown(_))));
+    }
+
+    #[test]
+    fn jobs_submitted_before_the_pool_is_dropped_still_run() {
+        let pool = ThreadPool::new(1);
+        let handle = pool.handle();
+        let (done, finished) = mpsc::channel();
+
+        handle.execute(move || {
+            thread::sleep(Duration::from_millis(10));
+            done.send(()).unwrap();
+        });
+        drop(pool);
+
+        assert!(finished.try_recv().is_ok());
+    }
+}
@@ -0,0 +1,195 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::queue::{self, Queued};
+
+/// The priority of a job. Jobs with a higher priority are started first.
+///
+/// Any value from 0 to 255 can be used, the named constants are just convenient points along that range.
+/// Jobs of the same priority are started in the order they were submitted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Priority(pub u8);
+
+impl Priority {
+    pub const LOW: Priority = Priority(64);
+    pub const NORMAL: Priority = Priority(128);
+    pub const HIGH: Priority = Priority(192);
+}
+
+impl Default for Priority {
+    fn default() -> Priority {
+        Priority::NORMAL
+    }
+}
+
+// A queue that hands out the job with the highest priority first, with aging so that low priority jobs
+// can't be starved forever: for every `aging` interval a job has been waiting it is treated as one point
+// higher priority.
+//
+// Jobs are kept in a FIFO per priority, so the oldest job of each priority is always at the front and
+// only those fronts need comparing.
+pub(crate) struct PriorityQueue {
+    levels: BTreeMap<Priority, VecDeque<Queued>>,
+    len: usize,
+    aging: Duration,
+}
+
+impl PriorityQueue {
+    pub(crate) fn new(aging: Duration) -> PriorityQueue {
+        PriorityQueue {
+            levels: BTreeMap::new(),
+            len: 0,
+            aging,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn push(&mut self, priority: Priority, queued: Queued) {
+        self.levels.entry(priority).or_default().push_back(queued);
+        self.len += 1;
+    }
+
+    // Hands out the job with the highest priority once aging is taken into account
+    pub(crate) fn pop(&mut self) -> Option<Queued> {
+        // Usually everything has the same priority, in which case there is nothing to compare
+        let priority = if self.levels.len() == 1 {
+            *self.levels.keys().next()?
+        } else {
+            let now = Instant::now();
+            let aging = self.aging.as_nanos().max(1);
+
+            // max_by_key returns the last of equal elements, and we iterate from lowest to highest priority,
+            // so on a tie the job that started out with the higher priority wins
+            let (priority, _) = self.levels.iter().max_by_key(|(priority, jobs)| {
+                let waited = now.saturating_duration_since(jobs[0].enqueued).as_nanos();
+                priority.0 as u128 + waited / aging
+            })?;
+            *priority
+        };
+
+        self.remove(priority, 0)
+    }
+
+    // Takes out the job that has been waiting longest, whatever its priority, to make room for a new one.
+    // Jobs queued past capacity are skipped, as the pool relies on them running, see `JobInfo::past_capacity`.
+    pub(crate) fn pop_oldest(&mut self) -> Option<Queued> {
+        let (priority, index) = self
+            .levels
+            .iter()
+            .filter_map(|(priority, jobs)| {
+                let index = jobs.iter().position(|queued| !queued.info.past_capacity)?;
+                Some((*priority, index, jobs[index].enqueued))
+            })
+            .min_by_key(|(_, _, enqueued)| *enqueued)
+            .map(|(priority, index, _)| (priority, index))?;
+
+        self.remove(priority, index)
+    }
+
+    // Empties the queue, returning the jobs in the order they would have been handed out
+    pub(crate) fn drain(&mut self) -> Vec<Queued> {
+        let mut jobs = Vec::with_capacity(self.len);
+        while let Some(queued) = self.pop() {
+            jobs.push(queued);
+        }
+        jobs
+    }
+
+    // Takes out every job whose token has been cancelled
+    pub(crate) fn remove_cancelled(&mut self) -> Vec<Queued> {
+        let mut removed = Vec::new();
+
+        for jobs in self.levels.values_mut() {
+            removed.extend(queue::take_cancelled(jobs));
+        }
+
+        self.levels.retain(|_, jobs| !jobs.is_empty());
+        self.len -= removed.len();
+
+        removed
+    }
+
+    // The highest priority of any queued job, not counting aging
+    pub(crate) fn top_priority(&self) -> Option<Priority> {
+        self.levels.keys().next_back().copied()
+    }
+
+    fn remove(&mut self, priority: Priority, index: usize) -> Option<Queued> {
+        let jobs = self.levels.get_mut(&priority)?;
+        let queued = jobs.remove(index)?;
+
+        // Empty levels are removed so that every level in the map always has a job at the front
+        if jobs.is_empty() {
+            self.levels.remove(&priority);
+        }
+        self.len -= 1;
+
+        Some(queued)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::PriorityQueue;
+    use crate::{
+        queue::{JobInfo, Queued},
+        Priority,
+    };
+
+    fn push(queue: &mut PriorityQueue, priority: Priority, name: &str) {
+        let info = JobInfo { label: Some(name.into()), ..JobInfo::default() };
+        queue.push(priority, Queued::new(Box::new(|| {}), info));
+    }
+
+    fn name(queued: Option<Queued>) -> String {
+        queued.and_then(|queued| queued.info.label).as_deref().unwrap_or_default().to_owned()
+    }
+
+    #[test]
+    fn highest_priority_first_and_fifo_within_a_level() {
+        let mut queue = PriorityQueue::new(Duration::from_secs(3600));
+
+        push(&mut queue, Priority::NORMAL, "normal 1");
+        push(&mut queue, Priority::LOW, "low");
+        push(&mut queue, Priority::HIGH, "high 1");
+        push(&mut queue, Priority::NORMAL, "normal 2");
+        push(&mut queue, Priority::HIGH, "high 2");
+        assert_eq!(queue.len(), 5);
+        assert_eq!(queue.top_priority(), Some(Priority::HIGH));
+
+        let order: Vec<_> = (0..5).map(|_| name(queue.pop())).collect();
+        assert_eq!(order, ["high 1", "high 2", "normal 1", "normal 2", "low"]);
+        assert!(queue.pop().is_none());
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn aging_lets_a_low_job_overtake_normal_ones() {
+        // With a tiny interval every microsecond spent waiting is worth a point, so the low job waiting a
+        // millisecond has long overtaken the normal jobs queued after it
+        let mut queue = PriorityQueue::new(Duration::from_micros(1));
+        push(&mut queue, Priority::LOW, "low");
+        thread::sleep(Duration::from_millis(1));
+        push(&mut queue, Priority::NORMAL, "normal 1");
+        push(&mut queue, Priority::NORMAL, "normal 2");
+
+        assert_eq!(name(queue.pop()), "low");
+
+        // With an interval of an hour it stays behind them
+        let mut queue = PriorityQueue::new(Duration::from_secs(3600));
+        push(&mut queue, Priority::LOW, "low");
+        thread::sleep(Duration::from_millis(1));
+        push(&mut queue, Priority::NORMAL, "normal 1");
+        push(&mut queue, Priority::NORMAL, "normal 2");
+
+        let order: Vec<_> = (0..3).map(|_| name(queue.pop())).collect();
+        assert_eq!(order, ["normal 1", "normal 2", "low"]);
+    }
+}
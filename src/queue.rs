@@ -0,0 +1,273 @@
+use std::{
+    collections::VecDeque,
+    mem,
+    sync::{Arc, Condvar, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use crate::{idle::Pending, lock, priority::PriorityQueue, CancellationToken, Job, Priority};
+
+/// What `ThreadPool::execute` does when the job queue is already at capacity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Block the submitting thread until a worker takes a job off the queue.
+    #[default]
+    Block,
+    /// Refuse the job and hand it back to the caller in `ExecuteError::QueueFull`.
+    Reject,
+    /// Throw away the job that has been waiting the longest to make room for the new one.
+    ///
+    /// Jobs the pool queues itself to carry on work it has already started, such as the next job
+    /// submitted with [`ThreadPool::execute_keyed`](crate::ThreadPool::execute_keyed), are never thrown away.
+    DropOldest,
+    /// Run the job straight away on the thread that submitted it.
+    CallerRuns,
+}
+
+// Anything that can be queued as a job. Every closure is one, and the pool has its own types for jobs it wraps
+// around the caller's closure, so that it can hand the closure back from a rejected job, see `ExecuteError::map`.
+pub(crate) trait Runnable: Send + 'static {
+    fn run(self);
+
+    fn into_job(self) -> Job
+    where
+        Self: Sized,
+    {
+        Box::new(move || self.run())
+    }
+}
+
+impl<F: FnOnce() + Send + 'static> Runnable for F {
+    fn run(self) {
+        self()
+    }
+}
+
+pub(crate) enum PushError<F> {
+    Closed(F),
+    Full(F),
+}
+
+// The optional extras a job can be submitted with
+#[derive(Default)]
+pub(crate) struct JobInfo {
+    // Cancelling the token takes the job off the queue
+    pub(crate) token: Option<CancellationToken>,
+    // Identifies the job to the watchdog
+    pub(crate) label: Option<Arc<str>>,
+    // How long the job may run before the watchdog reports it, instead of the pool-wide budget
+    pub(crate) budget: Option<Duration>,
+    // Keeps the pool from counting as idle until the job has run or been dropped
+    pub(crate) pending: Option<Pending>,
+    // Set for jobs the pool queues itself to carry on work it already accepted, see `Shared::requeue`.
+    // They go on the queue even when it is full, as blocking or running them inline on a worker could deadlock or recurse,
+    // and DropOldest never drops them to make room, as that would strand the work they carry on.
+    pub(crate) past_capacity: bool,
+}
+
+// A job along with when it was queued and the extras it was submitted with
+pub(crate) struct Queued {
+    pub(crate) job: Job,
+    pub(crate) enqueued: Instant,
+    pub(crate) info: JobInfo,
+}
+
+impl Queued {
+    pub(crate) fn new(job: Job, info: JobInfo) -> Queued {
+        Queued {
+            job,
+            enqueued: Instant::now(),
+            info,
+        }
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.info.token.as_ref().is_some_and(CancellationToken::is_cancelled)
+    }
+}
+
+// Takes the jobs whose token has been cancelled out of a FIFO, keeping the rest in order
+pub(crate) fn take_cancelled(jobs: &mut VecDeque<Queued>) -> Vec<Queued> {
+    let mut cancelled = Vec::new();
+
+    for queued in mem::take(jobs) {
+        if queued.is_cancelled() {
+            cancelled.push(queued);
+        } else {
+            jobs.push_back(queued);
+        }
+    }
+
+    cancelled
+}
+
+pub(crate) enum Pop {
+    Job(Queued),
+    TimedOut,
+    Closed,
+}
+
+struct QueueState {
+    jobs: PriorityQueue,
+    // Once closed no new jobs are accepted, but the jobs already queued are still handed out
+    closed: bool,
+    // Number of workers currently waiting for a job
+    idle: usize,
+}
+
+// A multiple producer, multiple consumer queue of jobs with an optional capacity, handed out by priority.
+// This replaces the mpsc channel, which can't be bounded and only has a single consumer.
+pub(crate) struct JobQueue {
+    state: Mutex<QueueState>,
+    // Signalled when a job is pushed or the queue is closed
+    available: Condvar,
+    // Signalled when a job is popped or the queue is closed
+    space: Condvar,
+    capacity: Option<usize>,
+    policy: OverflowPolicy,
+}
+
+impl JobQueue {
+    pub(crate) fn new(capacity: Option<usize>, policy: OverflowPolicy, aging: Duration) -> JobQueue {
+        JobQueue {
+            state: Mutex::new(QueueState {
+                jobs: PriorityQueue::new(aging),
+                closed: false,
+                idle: 0,
+            }),
+            available: Condvar::new(),
+            space: Condvar::new(),
+            capacity,
+            policy,
+        }
+    }
+
+    pub(crate) fn policy(&self) -> OverflowPolicy {
+        self.policy
+    }
+
+    // The closure is only boxed once we know it will be queued, so that it can be handed back on failure.
+    // Under DropOldest the job that was dropped to make room is returned, for the caller to count and drop.
+    pub(crate) fn push<F>(&self, f: F, priority: Priority, info: JobInfo) -> Result<Option<Queued>, PushError<F>>
+    where
+        F: Runnable,
+    {
+        let mut state = lock(&self.state);
+        let mut dropped = None;
+
+        if let Some(capacity) = self.capacity.filter(|_| !info.past_capacity) {
+            match self.policy {
+                OverflowPolicy::Block => {
+                    while !state.closed && state.jobs.len() >= capacity {
+                        state = self.space.wait(state).unwrap_or_else(PoisonError::into_inner);
+                    }
+                }
+                OverflowPolicy::DropOldest => {
+                    // If every queued job is one the pool queued past capacity there is nothing to drop,
+                    // and the new job joins them
+                    if state.jobs.len() >= capacity {
+                        dropped = state.jobs.pop_oldest();
+                    }
+                }
+                OverflowPolicy::Reject | OverflowPolicy::CallerRuns => {
+                    if state.jobs.len() >= capacity {
+                        return Err(PushError::Full(f));
+                    }
+                }
+            }
+        }
+
+        if state.closed {
+            return Err(PushError::Closed(f));
+        }
+
+        state.jobs.push(priority, Queued::new(f.into_job(), info));
+        drop(state);
+        self.available.notify_one();
+
+        Ok(dropped)
+    }
+
+    // Blocks until a job is available, the queue is closed and empty, or the timeout (if any) runs out.
+    pub(crate) fn pop(&self, timeout: Option<Duration>) -> Pop {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut state = lock(&self.state);
+
+        state.idle += 1;
+        let pop = loop {
+            if let Some(queued) = state.jobs.pop() {
+                break Pop::Job(queued);
+            }
+
+            if state.closed {
+                break Pop::Closed;
+            }
+
+            match deadline {
+                None => {
+                    state = self.available.wait(state).unwrap_or_else(PoisonError::into_inner);
+                }
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break Pop::TimedOut;
+                    }
+
+                    state = self
+                        .available
+                        .wait_timeout(state, deadline - now)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0;
+                }
+            }
+        };
+        state.idle -= 1;
+        drop(state);
+
+        if let Pop::Job(_) = pop {
+            self.space.notify_one();
+        }
+
+        pop
+    }
+
+    pub(crate) fn close(&self) {
+        lock(&self.state).closed = true;
+
+        // wake everyone up so that blocked submitters give up and idle workers exit
+        self.available.notify_all();
+        self.space.notify_all();
+    }
+
+    // Takes every job out of the queue
+    pub(crate) fn drain(&self) -> Vec<Job> {
+        let jobs = lock(&self.state).jobs.drain();
+        self.space.notify_all();
+        jobs.into_iter().map(|queued| queued.job).collect()
+    }
+
+    // Takes the jobs whose token has been cancelled out of the queue and drops them. Returns how many were dropped.
+    pub(crate) fn remove_cancelled(&self) -> usize {
+        let removed = lock(&self.state).jobs.remove_cancelled();
+
+        if !removed.is_empty() {
+            self.space.notify_all();
+        }
+
+        removed.len()
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        lock(&self.state).closed
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        lock(&self.state).jobs.len()
+    }
+
+    // True when there are more jobs waiting than there are idle workers to take them
+    pub(crate) fn is_backed_up(&self) -> bool {
+        let state = lock(&self.state);
+        state.jobs.len() > state.idle
+    }
+}
@@ -0,0 +1,157 @@
+use std::time::Duration;
+
+use crate::{
+    queue::{JobInfo, JobQueue, Pop, PushError, Queued, Runnable},
+    simulation::SimulatedQueue,
+    stealing::{Registration, StealingQueue},
+    Job, OverflowPolicy, Priority,
+};
+
+/// How jobs are handed from the pool to its workers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    /// A single queue shared by every worker. Jobs of the same priority are always started in the order they were submitted.
+    #[default]
+    Channel,
+    /// Every worker has its own deque and steals from the others when it runs out of work.
+    ///
+    /// This cuts down on lock contention with many workers or many small jobs, particularly jobs that
+    /// submit more jobs, at the cost of jobs no longer being started in strict submission order.
+    WorkStealing,
+    /// No worker threads at all: jobs only run when the pool is stepped, one at a time on the calling thread.
+    ///
+    /// Every step runs one of the queued jobs picked at random, using a generator seeded with `seed`.
+    /// The same jobs submitted in the same order with the same seed always run in the same order, which makes
+    /// ordering bugs reproducible in tests, and trying a range of seeds shakes out orders that rarely happen for real.
+    /// See `ThreadPool::step` and `ThreadPool::run_until_idle`.
+    ///
+    /// Priorities are ignored, and so is [`OverflowPolicy::Block`]: with nothing running in the background a
+    /// full queue would never make room, so the job is rejected instead. `ThreadPool::wait_idle`, `ThreadPool::scope`,
+    /// `JobGroup::wait` and dropping the pool run the queued jobs themselves, but anything else that waits for a job, like
+    /// `JobHandle::join`, only returns once the job has been stepped. Jobs scheduled for later are still queued
+    /// by the timer thread in real time.
+    Simulated {
+        /// Seeds the generator that picks which job runs next.
+        seed: u64,
+    },
+}
+
+// Dispatches to whichever queue the pool was built with
+pub(crate) enum Scheduler {
+    Channel(JobQueue),
+    Stealing(StealingQueue),
+    Simulated(SimulatedQueue),
+}
+
+impl Scheduler {
+    pub(crate) fn new(
+        backend: Backend,
+        capacity: Option<usize>,
+        policy: OverflowPolicy,
+        aging: Duration,
+    ) -> Scheduler {
+        match backend {
+            Backend::Channel => Scheduler::Channel(JobQueue::new(capacity, policy, aging)),
+            Backend::WorkStealing => Scheduler::Stealing(StealingQueue::new(capacity, policy, aging)),
+            Backend::Simulated { seed } => Scheduler::Simulated(SimulatedQueue::new(seed, capacity, policy)),
+        }
+    }
+
+    // Called on each worker thread as it starts. The worker stays registered until the returned value is dropped.
+    pub(crate) fn register_worker(&self) -> Option<Registration<'_>> {
+        match self {
+            Scheduler::Channel(_) | Scheduler::Simulated(_) => None,
+            Scheduler::Stealing(queue) => Some(queue.register_worker()),
+        }
+    }
+
+    pub(crate) fn policy(&self) -> OverflowPolicy {
+        match self {
+            Scheduler::Channel(queue) => queue.policy(),
+            Scheduler::Stealing(queue) => queue.policy(),
+            Scheduler::Simulated(queue) => queue.policy(),
+        }
+    }
+
+    // Returns the job that was dropped to make room for this one under DropOldest
+    pub(crate) fn push<F>(&self, f: F, priority: Priority, info: JobInfo) -> Result<Option<Queued>, PushError<F>>
+    where
+        F: Runnable,
+    {
+        match self {
+            Scheduler::Channel(queue) => queue.push(f, priority, info),
+            Scheduler::Stealing(queue) => queue.push(f, priority, info),
+            Scheduler::Simulated(queue) => queue.push(f, info),
+        }
+    }
+
+    pub(crate) fn pop(&self, timeout: Option<Duration>) -> Pop {
+        match self {
+            Scheduler::Channel(queue) => queue.pop(timeout),
+            Scheduler::Stealing(queue) => queue.pop(timeout),
+            Scheduler::Simulated(_) => unreachable!("a simulated pool has no workers to pop jobs"),
+        }
+    }
+
+    pub(crate) fn close(&self) {
+        match self {
+            Scheduler::Channel(queue) => queue.close(),
+            Scheduler::Stealing(queue) => queue.close(),
+            Scheduler::Simulated(queue) => queue.close(),
+        }
+    }
+
+    pub(crate) fn drain(&self) -> Vec<Job> {
+        match self {
+            Scheduler::Channel(queue) => queue.drain(),
+            Scheduler::Stealing(queue) => queue.drain(),
+            Scheduler::Simulated(queue) => queue.drain(),
+        }
+    }
+
+    // Drops the queued jobs whose token has been cancelled, returning how many there were
+    pub(crate) fn remove_cancelled(&self) -> usize {
+        match self {
+            Scheduler::Channel(queue) => queue.remove_cancelled(),
+            Scheduler::Stealing(queue) => queue.remove_cancelled(),
+            Scheduler::Simulated(queue) => queue.remove_cancelled(),
+        }
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        match self {
+            Scheduler::Channel(queue) => queue.is_closed(),
+            Scheduler::Stealing(queue) => queue.is_closed(),
+            Scheduler::Simulated(queue) => queue.is_closed(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Scheduler::Channel(queue) => queue.len(),
+            Scheduler::Stealing(queue) => queue.len(),
+            Scheduler::Simulated(queue) => queue.len(),
+        }
+    }
+
+    pub(crate) fn is_backed_up(&self) -> bool {
+        match self {
+            Scheduler::Channel(queue) => queue.is_backed_up(),
+            Scheduler::Stealing(queue) => queue.is_backed_up(),
+            // There are no workers to start, however many jobs are waiting
+            Scheduler::Simulated(_) => false,
+        }
+    }
+
+    pub(crate) fn is_simulated(&self) -> bool {
+        matches!(self, Scheduler::Simulated(_))
+    }
+
+    // Takes the next job a simulated pool should run. Always None for the other backends, whose workers pop jobs themselves.
+    pub(crate) fn next_simulated(&self) -> Option<Queued> {
+        match self {
+            Scheduler::Simulated(queue) => queue.next(),
+            Scheduler::Channel(_) | Scheduler::Stealing(_) => None,
+        }
+    }
+}
@@ -0,0 +1,279 @@
+use std::{
+    any::Any,
+    marker::PhantomData,
+    mem,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex, PoisonError},
+};
+
+use crate::{lock, Job, ThreadPool};
+
+/// A scope for running jobs that borrow from the stack, created by `ThreadPool::scope`.
+///
+/// Every job executed through the scope is finished before `ThreadPool::scope` returns,
+/// which is what lets the jobs borrow anything that outlives the scope.
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: &'env ThreadPool,
+    state: Arc<ScopeState>,
+    // The same invariance markers std::thread::Scope uses, so the lifetimes can't be shrunk or stretched
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+struct ScopeState {
+    // Jobs that have been submitted and not yet run or dropped
+    pending: Mutex<usize>,
+    // Signalled when pending drops to zero
+    done: Condvar,
+    // The first panic from any of the scope's jobs, re-raised once they have all finished
+    panic: Mutex<Option<Box<dyn Any + Send>>>,
+}
+
+impl ScopeState {
+    fn wait(&self) {
+        let mut pending = lock(&self.pending);
+        while *pending > 0 {
+            pending = self.done.wait(pending).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+// Lives inside each job's closure, so the job counts as finished whether it is run or dropped without running
+struct PendingGuard(Arc<ScopeState>);
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        let mut pending = lock(&self.0.pending);
+        *pending -= 1;
+
+        if *pending == 0 {
+            self.0.done.notify_all();
+        }
+    }
+}
+
+// What each job's closure captures. Fields are dropped in the order they are declared, so when the job is dropped
+// without running, `f` and everything it borrows is gone before `pending` lets the scope return.
+// This holds even if dropping `f` panics, as the rest of the fields are still dropped while unwinding.
+struct ScopedJob<F> {
+    f: F,
+    pending: PendingGuard,
+}
+
+impl<F: FnOnce()> ScopedJob<F> {
+    fn run(self) {
+        let ScopedJob { f, pending } = self;
+
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(f)) {
+            lock(&pending.0.panic).get_or_insert(payload);
+        }
+    }
+}
+
+impl<'scope> Scope<'scope, '_> {
+    /// Execute a job on the pool that may borrow anything that outlives the scope.
+    ///
+    /// If the pool won't take the job (it is full and rejects jobs, or is shutting down),
+    /// the job is run straight away on the calling thread instead.
+    pub fn execute<F>(&'scope self, f: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        *lock(&self.state.pending) += 1;
+
+        let scoped = ScopedJob { f, pending: PendingGuard(Arc::clone(&self.state)) };
+        let job: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || scoped.run());
+
+        // SAFETY: the pool only accepts 'static jobs, but `ThreadPool::scope` doesn't return until `pending` is back
+        // to zero, and `pending` only goes down once `f` has been run or dropped, see `ScopedJob`. So nothing in the
+        // closure can outlive what it borrows, even if the pool drops it unrun or hands it out from `shutdown_now`.
+        let job: Job = unsafe { mem::transmute::<Box<dyn FnOnce() + Send + 'scope>, Job>(job) };
+
+        if let Err(e) = self.pool.try_execute(job) {
+            (e.into_inner())();
+        }
+    }
+}
+
+pub(crate) fn scope<'env, F, T>(pool: &'env ThreadPool, f: F) -> T
+where
+    F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+{
+    let scope = Scope {
+        pool,
+        state: Arc::new(ScopeState {
+            pending: Mutex::new(0),
+            done: Condvar::new(),
+            panic: Mutex::new(None),
+        }),
+        scope: PhantomData,
+        env: PhantomData,
+    };
+
+    // Even if `f` panics, the jobs it already started may borrow from the stack we're about to unwind, so wait for them first
+    let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+
+    pool.shared.run_if_simulated();
+    scope.state.wait();
+
+    let job_panic = lock(&scope.state.panic).take();
+
+    match (result, job_panic) {
+        (Err(payload), _) | (Ok(_), Some(payload)) => panic::resume_unwind(payload),
+        (Ok(result), None) => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        panic::{self, AssertUnwindSafe},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc, Arc, Condvar, Mutex,
+        },
+        thread,
+        time::Duration,
+    };
+
+    use super::{PendingGuard, ScopeState, ScopedJob};
+    use crate::{lock, ThreadPool};
+
+    fn new_state(pending: usize) -> Arc<ScopeState> {
+        Arc::new(ScopeState {
+            pending: Mutex::new(pending),
+            done: Condvar::new(),
+            panic: Mutex::new(None),
+        })
+    }
+
+    // Records how many jobs the scope was still waiting for at the moment it is dropped
+    struct SeesPending(Arc<ScopeState>, Arc<Mutex<Option<usize>>>);
+
+    impl Drop for SeesPending {
+        fn drop(&mut self) {
+            *self.1.lock().unwrap() = Some(*lock(&self.0.pending));
+        }
+    }
+
+    #[test]
+    fn unrun_job_drops_closure_before_releasing_scope() {
+        let state = new_state(1);
+        let seen = Arc::new(Mutex::new(None));
+        let captured = SeesPending(Arc::clone(&state), Arc::clone(&seen));
+
+        let job = ScopedJob {
+            f: move || drop(captured),
+            pending: PendingGuard(Arc::clone(&state)),
+        };
+        drop(job);
+
+        // The job still counted as pending when its closure was dropped
+        assert_eq!(*seen.lock().unwrap(), Some(1));
+        assert_eq!(*lock(&state.pending), 0);
+    }
+
+    #[test]
+    fn job_dropped_by_shutdown_now_is_gone_before_scope_returns() {
+        let pool = ThreadPool::new(1);
+        let seen = Arc::new(Mutex::new(None));
+        let (release, blocked) = mpsc::channel::<()>();
+        let (started, running) = mpsc::channel::<()>();
+
+        pool.scope(|s| {
+            // Keep the only worker busy so the next job stays queued
+            s.execute(move || {
+                started.send(()).unwrap();
+                let _ = blocked.recv();
+            });
+            running.recv().unwrap();
+
+            let captured = SeesPending(Arc::clone(&s.state), Arc::clone(&seen));
+            s.execute(move || drop(captured));
+
+            drop(pool.shutdown_now());
+            release.send(()).unwrap();
+        });
+
+        // Both jobs still counted as pending when the unrun one's closure was dropped
+        assert_eq!(*seen.lock().unwrap(), Some(2));
+    }
+
+    #[test]
+    fn jobs_borrow_from_the_stack() {
+        let pool = ThreadPool::new(4);
+        let items: Vec<usize> = (1..=100).collect();
+        let total = AtomicUsize::new(0);
+        let mut results = vec![0; items.len()];
+
+        pool.scope(|s| {
+            for (item, result) in items.iter().zip(results.iter_mut()) {
+                let total = &total;
+                s.execute(move || {
+                    *result = item * 2;
+                    total.fetch_add(*item, Ordering::SeqCst);
+                });
+            }
+        });
+
+        assert_eq!(total.load(Ordering::SeqCst), 5050);
+        assert_eq!(results, items.iter().map(|item| item * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn job_panic_is_resumed_after_every_job_finishes() {
+        let pool = ThreadPool::new(2);
+        let finished = AtomicUsize::new(0);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|s| {
+                s.execute(|| panic!("job failed"));
+                for _ in 0..4 {
+                    s.execute(|| {
+                        thread::sleep(Duration::from_millis(20));
+                        finished.fetch_add(1, Ordering::SeqCst);
+                    });
+                }
+            })
+        }));
+
+        let payload = result.unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"job failed"));
+        assert_eq!(finished.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn panic_in_f_waits_for_started_jobs() {
+        let pool = ThreadPool::new(2);
+        let finished = AtomicUsize::new(0);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|s| {
+                s.execute(|| {
+                    thread::sleep(Duration::from_millis(50));
+                    finished.fetch_add(1, Ordering::SeqCst);
+                });
+                panic!("scope failed");
+            })
+        }));
+
+        let payload = result.unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"scope failed"));
+        assert_eq!(finished.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn rejected_job_runs_inline() {
+        let pool = ThreadPool::new(1);
+        pool.shutdown(Duration::from_secs(1));
+
+        let caller = thread::current().id();
+        let mut ran_on = None;
+
+        pool.scope(|s| {
+            s.execute(|| ran_on = Some(thread::current().id()));
+        });
+
+        assert_eq!(ran_on, Some(caller));
+    }
+}
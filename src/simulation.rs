@@ -0,0 +1,289 @@
+use std::{
+    mem,
+    sync::Mutex,
+};
+
+use crate::{
+    lock,
+    queue::{JobInfo, PushError, Queued, Runnable},
+    Job, OverflowPolicy,
+};
+
+struct SimState {
+    // Kept in the order the jobs were submitted, which DropOldest relies on
+    jobs: Vec<Queued>,
+    // The state of the random number generator that picks the next job
+    rng: u64,
+    closed: bool,
+}
+
+// The queue behind `Backend::Simulated`. Nothing pops from it on its own: jobs are only run when the pool is stepped,
+// and each step picks one of the queued jobs at random. The choice only depends on the seed and on what was queued,
+// so the same jobs submitted in the same order are always run in the same order.
+pub(crate) struct SimulatedQueue {
+    state: Mutex<SimState>,
+    capacity: Option<usize>,
+    policy: OverflowPolicy,
+}
+
+impl SimulatedQueue {
+    pub(crate) fn new(seed: u64, capacity: Option<usize>, policy: OverflowPolicy) -> SimulatedQueue {
+        SimulatedQueue {
+            state: Mutex::new(SimState { jobs: Vec::new(), rng: seed, closed: false }),
+            capacity,
+            policy,
+        }
+    }
+
+    pub(crate) fn policy(&self) -> OverflowPolicy {
+        self.policy
+    }
+
+    // Returns the job that was dropped to make room under DropOldest, see `JobQueue::push`
+    pub(crate) fn push<F>(&self, f: F, info: JobInfo) -> Result<Option<Queued>, PushError<F>>
+    where
+        F: Runnable,
+    {
+        let mut state = lock(&self.state);
+        let mut dropped = None;
+
+        if state.closed {
+            return Err(PushError::Closed(f));
+        }
+
+        if let Some(capacity) = self.capacity.filter(|_| !info.past_capacity) {
+            if state.jobs.len() >= capacity {
+                // Nothing makes room in the queue until the pool is stepped, so blocking would never return.
+                // Block is treated like Reject instead.
+                if self.policy != OverflowPolicy::DropOldest {
+                    return Err(PushError::Full(f));
+                }
+                // Jobs queued past capacity are never dropped, if there is nothing else the new job joins them
+                if let Some(index) = state.jobs.iter().position(|queued| !queued.info.past_capacity) {
+                    dropped = Some(state.jobs.remove(index));
+                }
+            }
+        }
+
+        state.jobs.push(Queued::new(f.into_job(), info));
+
+        Ok(dropped)
+    }
+
+    // Takes a queued job at random, or None if the queue is empty
+    pub(crate) fn next(&self) -> Option<Queued> {
+        let mut state = lock(&self.state);
+
+        if state.jobs.is_empty() {
+            return None;
+        }
+
+        let index = (split_mix(&mut state.rng) % state.jobs.len() as u64) as usize;
+        Some(state.jobs.remove(index))
+    }
+
+    pub(crate) fn close(&self) {
+        lock(&self.state).closed = true;
+    }
+
+    pub(crate) fn drain(&self) -> Vec<Job> {
+        let jobs = mem::take(&mut lock(&self.state).jobs);
+        jobs.into_iter().map(|queued| queued.job).collect()
+    }
+
+    pub(crate) fn remove_cancelled(&self) -> usize {
+        let removed: Vec<_> = lock(&self.state).jobs.extract_if(.., |queued| queued.is_cancelled()).collect();
+        removed.len()
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        lock(&self.state).closed
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        lock(&self.state).jobs.len()
+    }
+}
+
+// SplitMix64, which is tiny and good enough to shuffle jobs. See https://prng.di.unimi.it/splitmix64.c
+fn split_mix(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    };
+
+    use crate::{Backend, OverflowPolicy, ThreadPool, ThreadPoolBuilder};
+
+    fn simulated(seed: u64) -> ThreadPoolBuilder {
+        ThreadPool::builder().backend(Backend::Simulated { seed })
+    }
+
+    // Submits jobs that each record their number, and steps the pool until they have all run
+    fn run_order(seed: u64, jobs: usize) -> Vec<usize> {
+        let pool = simulated(seed).build().unwrap();
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        for i in 0..jobs {
+            let order = Arc::clone(&order);
+            pool.execute(move || order.lock().unwrap().push(i));
+        }
+
+        assert_eq!(pool.run_until_idle(), jobs);
+        let order = order.lock().unwrap().clone();
+        order
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_order() {
+        for seed in 0..10 {
+            assert_eq!(run_order(seed, 50), run_order(seed, 50));
+        }
+    }
+
+    #[test]
+    fn different_seeds_give_different_orders() {
+        let first = run_order(1, 50);
+        assert_ne!(first, run_order(2, 50));
+
+        let mut sorted = first.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, (0..50).collect::<Vec<_>>());
+        assert_ne!(first, sorted);
+    }
+
+    #[test]
+    fn nothing_runs_until_stepped() {
+        let pool = simulated(0).build().unwrap();
+        let ran = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..3 {
+            let ran = Arc::clone(&ran);
+            pool.execute(move || {
+                ran.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        assert_eq!(pool.worker_count(), 0);
+        assert_eq!(ran.load(Ordering::SeqCst), 0);
+
+        assert!(pool.step());
+        assert_eq!(ran.load(Ordering::SeqCst), 1);
+        assert_eq!(pool.queue_len(), 2);
+
+        assert_eq!(pool.run_until_idle(), 2);
+        assert!(!pool.step());
+    }
+
+    #[test]
+    fn jobs_submitted_by_jobs_are_stepped_too() {
+        let pool = simulated(3).build().unwrap();
+        let handle = pool.handle();
+        let ran = Arc::new(AtomicUsize::new(0));
+
+        let inner = Arc::clone(&ran);
+        pool.execute(move || {
+            inner.fetch_add(1, Ordering::SeqCst);
+            let inner = Arc::clone(&inner);
+            handle.execute(move || {
+                inner.fetch_add(1, Ordering::SeqCst);
+            });
+        });
+
+        assert_eq!(pool.run_until_idle(), 2);
+        assert_eq!(ran.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn full_queue_rejects_instead_of_blocking() {
+        for policy in [OverflowPolicy::Reject, OverflowPolicy::Block] {
+            let pool = simulated(0).queue_capacity(2).overflow_policy(policy).build().unwrap();
+
+            assert!(pool.try_execute(|| {}).is_ok());
+            assert!(pool.try_execute(|| {}).is_ok());
+            assert!(pool.try_execute(|| {}).is_err());
+            assert_eq!(pool.queue_len(), 2);
+        }
+    }
+
+    #[test]
+    fn full_queue_drops_the_oldest_job() {
+        let pool = simulated(0).queue_capacity(2).overflow_policy(OverflowPolicy::DropOldest).build().unwrap();
+        let ran = Arc::new(Mutex::new(Vec::new()));
+
+        for i in 0..4 {
+            let ran = Arc::clone(&ran);
+            pool.execute(move || ran.lock().unwrap().push(i));
+        }
+
+        assert_eq!(pool.queue_len(), 2);
+        pool.run_until_idle();
+
+        let mut ran = ran.lock().unwrap().clone();
+        ran.sort_unstable();
+        assert_eq!(ran, [2, 3]);
+    }
+
+    #[test]
+    fn wait_idle_steps_the_pool() {
+        let pool = simulated(5).build().unwrap();
+        let ran = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..10 {
+            let ran = Arc::clone(&ran);
+            pool.execute(move || {
+                ran.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        pool.wait_idle();
+        assert_eq!(ran.load(Ordering::SeqCst), 10);
+        assert_eq!(pool.queue_len(), 0);
+    }
+
+    #[test]
+    fn scope_steps_the_pool() {
+        let pool = simulated(7).build().unwrap();
+        let mut results = vec![0; 10];
+
+        pool.scope(|s| {
+            for (i, result) in results.iter_mut().enumerate() {
+                s.execute(move || *result = i * 2);
+            }
+        });
+
+        assert_eq!(results, (0..10).map(|i| i * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn group_wait_steps_the_pool() {
+        let pool = simulated(9).build().unwrap();
+        let group = pool.group();
+        let ran = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..5 {
+            let ran = Arc::clone(&ran);
+            group.execute(move || {
+                ran.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        assert!(group.wait().is_ok());
+        assert_eq!(ran.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    #[should_panic(expected = "Backend::Simulated")]
+    fn stepping_a_threaded_pool_panics() {
+        ThreadPool::new(1).step();
+    }
+}
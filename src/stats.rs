@@ -0,0 +1,196 @@
+use std::{
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+// Bucket i counts durations below 2^i microseconds, the last bucket also takes everything above that
+const BUCKETS: usize = 32;
+
+/// A snapshot of what a [`ThreadPool`](crate::ThreadPool) has been doing, from `ThreadPool::stats`.
+///
+/// The counters are read one after another while the pool keeps running,
+/// so they may be very slightly out of step with each other.
+#[derive(Debug, Clone)]
+pub struct PoolStats {
+    /// Jobs accepted onto the queue since the pool was created.
+    pub submitted: u64,
+    /// Jobs that ran to completion. Jobs started with `spawn` count as completed even if they panicked,
+    /// as the panic is handed to their `JobHandle` instead.
+    pub completed: u64,
+    /// Jobs that panicked.
+    pub panicked: u64,
+    /// Jobs the pool refused to take.
+    pub rejected: u64,
+    /// Jobs that were taken off the queue without running: dropped to make room under `OverflowPolicy::DropOldest`,
+    /// cancelled while queued, left over at the `ThreadPool::shutdown` deadline or handed back by `ThreadPool::shutdown_now`.
+    ///
+    /// Once the pool is idle, `submitted` is the sum of `completed`, `panicked` and `dropped`.
+    pub dropped: u64,
+    /// Jobs waiting in the queue right now.
+    pub queued: usize,
+    /// Workers running a job right now.
+    pub busy_workers: usize,
+    /// Workers waiting for a job right now.
+    pub idle_workers: usize,
+    /// How long jobs waited in the queue before a worker started them.
+    pub queue_wait: Histogram,
+    /// How long jobs took to run.
+    pub execution_time: Histogram,
+}
+
+/// A histogram of durations, with buckets that double in size: under 1µs, under 2µs, under 4µs and so on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS],
+    sum: Duration,
+}
+
+impl Histogram {
+    /// The number of durations recorded.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// The average of the recorded durations, or `None` if nothing has been recorded.
+    pub fn mean(&self) -> Option<Duration> {
+        let count = self.count();
+        (count > 0).then(|| Duration::from_nanos((self.sum.as_nanos() / count as u128) as u64))
+    }
+
+    /// An upper bound on the given quantile, e.g. `quantile(0.99)` for the 99th percentile.
+    ///
+    /// This is the upper edge of the bucket the quantile falls in, so it is accurate to within a factor of two.
+    /// Returns `None` if nothing has been recorded.
+    pub fn quantile(&self, quantile: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+
+        let target = ((count as f64 * quantile.clamp(0.0, 1.0)).ceil() as u64).max(1);
+        let mut seen = 0;
+
+        for (i, &bucket) in self.buckets.iter().enumerate() {
+            seen += bucket;
+            if seen >= target {
+                return Some(bucket_bound(i));
+            }
+        }
+
+        Some(bucket_bound(BUCKETS - 1))
+    }
+
+    /// The count in each bucket, paired with the bucket's upper bound.
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        self.buckets.iter().enumerate().map(|(i, &count)| (bucket_bound(i), count))
+    }
+}
+
+fn bucket_bound(i: usize) -> Duration {
+    Duration::from_micros(1 << i)
+}
+
+// The live version of a Histogram. Recording is a couple of relaxed atomic adds, so it's cheap enough to leave on.
+struct AtomicHistogram {
+    buckets: [AtomicU64; BUCKETS],
+    sum_nanos: AtomicU64,
+}
+
+impl AtomicHistogram {
+    fn new() -> AtomicHistogram {
+        AtomicHistogram {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, duration: Duration) {
+        let micros = duration.as_micros();
+
+        // The number of bits needed for the value is the index of the first bucket whose bound is above it
+        let bucket = (u128::BITS - micros.leading_zeros()) as usize;
+
+        self.buckets[bucket.min(BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos.fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> Histogram {
+        Histogram {
+            buckets: std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed)),
+            sum: Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+// The counters behind PoolStats, updated by the pool and its workers as they go
+pub(crate) struct Metrics {
+    submitted: AtomicU64,
+    completed: AtomicU64,
+    panicked: AtomicU64,
+    rejected: AtomicU64,
+    dropped: AtomicU64,
+    busy_workers: AtomicUsize,
+    queue_wait: AtomicHistogram,
+    execution_time: AtomicHistogram,
+}
+
+impl Metrics {
+    pub(crate) fn new() -> Metrics {
+        Metrics {
+            submitted: AtomicU64::new(0),
+            completed: AtomicU64::new(0),
+            panicked: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            busy_workers: AtomicUsize::new(0),
+            queue_wait: AtomicHistogram::new(),
+            execution_time: AtomicHistogram::new(),
+        }
+    }
+
+    pub(crate) fn job_submitted(&self) {
+        self.submitted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn job_rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn jobs_dropped(&self, count: usize) {
+        self.dropped.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn job_started(&self, waited: Duration) {
+        self.busy_workers.fetch_add(1, Ordering::Relaxed);
+        self.queue_wait.record(waited);
+    }
+
+    pub(crate) fn job_finished(&self, took: Duration, panicked: bool) {
+        self.execution_time.record(took);
+
+        if panicked {
+            self.panicked.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.completed.fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.busy_workers.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self, queued: usize, live_workers: usize) -> PoolStats {
+        let busy_workers = self.busy_workers.load(Ordering::Relaxed);
+
+        PoolStats {
+            submitted: self.submitted.load(Ordering::Relaxed),
+            completed: self.completed.load(Ordering::Relaxed),
+            panicked: self.panicked.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            queued,
+            busy_workers,
+            idle_workers: live_workers.saturating_sub(busy_workers),
+            queue_wait: self.queue_wait.snapshot(),
+            execution_time: self.execution_time.snapshot(),
+        }
+    }
+}
@@ -0,0 +1,673 @@
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, PoisonError, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    lock,
+    priority::PriorityQueue,
+    queue::{self, JobInfo, Pop, PushError, Queued, Runnable},
+    Job, OverflowPolicy, Priority,
+};
+
+// The most jobs a worker moves from the injector into its own deque in one go
+const MAX_BATCH: usize = 32;
+
+// Gives every queue its own id so a worker thread can tell whether it belongs to the queue it is pushing to
+static NEXT_QUEUE_ID: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // The local deque of the worker running on this thread, along with the id of the queue it belongs to
+    static CURRENT: RefCell<Option<(usize, Arc<LocalQueue>)>> = const { RefCell::new(None) };
+}
+
+// A worker's own deque. Only the owner pushes to it, but any worker can steal from it.
+struct LocalQueue {
+    jobs: Mutex<VecDeque<Queued>>,
+}
+
+struct Injector {
+    jobs: PriorityQueue,
+    closed: bool,
+}
+
+impl Injector {
+    fn pop(&mut self, urgent: &AtomicBool) -> Option<Queued> {
+        let queued = self.jobs.pop();
+        urgent.store(self.has_urgent(), Ordering::SeqCst);
+        queued
+    }
+
+    fn push(&mut self, priority: Priority, queued: Queued, urgent: &AtomicBool) {
+        self.jobs.push(priority, queued);
+        urgent.store(self.has_urgent(), Ordering::SeqCst);
+    }
+
+    fn has_urgent(&self) -> bool {
+        self.jobs.top_priority().is_some_and(|priority| priority > Priority::NORMAL)
+    }
+}
+
+// A work-stealing scheduler: every worker has its own deque, with a shared injector for jobs
+// submitted from outside the pool.
+//
+// Jobs submitted from a worker thread go straight onto that worker's deque, so they don't touch any shared lock.
+// Workers take jobs from their own deque first, then grab a batch from the injector, and only then steal from
+// other workers. That way the shared locks are only taken once per batch rather than once per job.
+//
+// Priorities are only kept in the injector: the deques are plain FIFOs, so jobs with a priority other than
+// normal always go through the injector, and workers check it first whenever it holds a job above normal.
+pub(crate) struct StealingQueue {
+    id: usize,
+    injector: Mutex<Injector>,
+    // Signalled when a job is pushed or the queue is closed. Always used with the injector lock.
+    available: Condvar,
+    // Signalled when a job is popped or the queue is closed. Always used with the injector lock.
+    space: Condvar,
+    locals: RwLock<Vec<Arc<LocalQueue>>>,
+    // Set while the injector holds a job above normal priority, which should be run before anything in the deques
+    urgent: AtomicBool,
+    // Jobs queued anywhere, counting ones that are about to be pushed
+    len: AtomicUsize,
+    // Number of workers waiting on `available`
+    sleepers: AtomicUsize,
+    // Number of submitters waiting on `space`
+    blocked: AtomicUsize,
+    closed: AtomicBool,
+    capacity: Option<usize>,
+    policy: OverflowPolicy,
+}
+
+// Registers a worker thread's local deque with the queue for as long as it is alive
+pub(crate) struct Registration<'a> {
+    queue: &'a StealingQueue,
+    local: Arc<LocalQueue>,
+}
+
+impl StealingQueue {
+    pub(crate) fn new(capacity: Option<usize>, policy: OverflowPolicy, aging: Duration) -> StealingQueue {
+        StealingQueue {
+            id: NEXT_QUEUE_ID.fetch_add(1, Ordering::Relaxed),
+            injector: Mutex::new(Injector {
+                jobs: PriorityQueue::new(aging),
+                closed: false,
+            }),
+            available: Condvar::new(),
+            space: Condvar::new(),
+            locals: RwLock::new(Vec::new()),
+            urgent: AtomicBool::new(false),
+            len: AtomicUsize::new(0),
+            sleepers: AtomicUsize::new(0),
+            blocked: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            capacity,
+            policy,
+        }
+    }
+
+    fn locals(&self) -> Vec<Arc<LocalQueue>> {
+        self.locals.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    // The current thread's deque, if it is one of our workers
+    fn current_local(&self) -> Option<Arc<LocalQueue>> {
+        CURRENT.with(|current| match &*current.borrow() {
+            Some((id, local)) if *id == self.id => Some(Arc::clone(local)),
+            _ => None,
+        })
+    }
+
+    pub(crate) fn register_worker(&self) -> Registration<'_> {
+        let local = Arc::new(LocalQueue {
+            jobs: Mutex::new(VecDeque::new()),
+        });
+
+        self.locals.write().unwrap_or_else(PoisonError::into_inner).push(Arc::clone(&local));
+        CURRENT.with(|current| *current.borrow_mut() = Some((self.id, Arc::clone(&local))));
+
+        Registration { queue: self, local }
+    }
+
+    pub(crate) fn policy(&self) -> OverflowPolicy {
+        self.policy
+    }
+
+    // Claims room for one more job, following the overflow policy if the queue is full.
+    // Along with the job, returns the one dropped to make room for it under DropOldest.
+    fn reserve<F>(&self, f: F) -> Result<(F, Option<Queued>), PushError<F>> {
+        let Some(capacity) = self.capacity else {
+            self.len.fetch_add(1, Ordering::SeqCst);
+            return Ok((f, None));
+        };
+
+        let try_reserve = || {
+            self.len
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |len| (len < capacity).then_some(len + 1))
+                .is_ok()
+        };
+
+        if try_reserve() {
+            return Ok((f, None));
+        }
+
+        match self.policy {
+            OverflowPolicy::Block => {
+                let mut injector = lock(&self.injector);
+                self.blocked.fetch_add(1, Ordering::SeqCst);
+
+                // Poppers only notify when they see a blocked submitter, so we must check again after registering
+                let reserved = loop {
+                    if injector.closed {
+                        break false;
+                    }
+                    if try_reserve() {
+                        break true;
+                    }
+                    injector = self.space.wait(injector).unwrap_or_else(PoisonError::into_inner);
+                };
+
+                self.blocked.fetch_sub(1, Ordering::SeqCst);
+
+                if reserved {
+                    Ok((f, None))
+                } else {
+                    Err(PushError::Closed(f))
+                }
+            }
+            OverflowPolicy::DropOldest => {
+                // The dropped job's slot is handed straight to the new one, so len doesn't change.
+                // Finding nothing to drop means someone else took the oldest job first and there is room again,
+                // or every queued job is one the pool queued past capacity, which the new job joins.
+                let oldest = self.take_oldest();
+                if oldest.is_none() {
+                    self.len.fetch_add(1, Ordering::SeqCst);
+                }
+
+                Ok((f, oldest))
+            }
+            OverflowPolicy::Reject | OverflowPolicy::CallerRuns => Err(PushError::Full(f)),
+        }
+    }
+
+    // Removes the job that has been waiting longest, which is at the front of the injector
+    // or failing that at the front of one of the workers' deques. Jobs queued past capacity are skipped.
+    fn take_oldest(&self) -> Option<Queued> {
+        let oldest = {
+            let mut injector = lock(&self.injector);
+            let oldest = injector.jobs.pop_oldest();
+            self.urgent.store(injector.has_urgent(), Ordering::SeqCst);
+            oldest
+        };
+        oldest.or_else(|| {
+            self.locals().iter().find_map(|local| {
+                let mut jobs = lock(&local.jobs);
+                let index = jobs.iter().position(|queued| !queued.info.past_capacity)?;
+                jobs.remove(index)
+            })
+        })
+    }
+
+    // Returns the job that was dropped to make room under DropOldest, see `JobQueue::push`
+    pub(crate) fn push<F>(&self, f: F, priority: Priority, info: JobInfo) -> Result<Option<Queued>, PushError<F>>
+    where
+        F: Runnable,
+    {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(PushError::Closed(f));
+        }
+
+        let (f, dropped) = if info.past_capacity {
+            self.len.fetch_add(1, Ordering::SeqCst);
+            (f, None)
+        } else {
+            self.reserve(f)?
+        };
+
+        // The deques don't know about priorities, so only normal jobs can go there
+        let local = self.current_local().filter(|_| priority == Priority::NORMAL);
+
+        match local {
+            // Our own workers can push to their deque even while closing, they drain it before they exit
+            Some(local) => lock(&local.jobs).push_back(Queued::new(f.into_job(), info)),
+            None => {
+                let mut injector = lock(&self.injector);
+
+                if injector.closed {
+                    drop(injector);
+                    self.release();
+                    return Err(PushError::Closed(f));
+                }
+
+                injector.push(priority, Queued::new(f.into_job(), info), &self.urgent);
+            }
+        }
+
+        self.wake_sleeper();
+
+        Ok(dropped)
+    }
+
+    // Workers only sleep after registering in `sleepers` and checking `len` once more, so if we see no sleepers
+    // here any worker about to sleep is guaranteed to see our job.
+    fn wake_sleeper(&self) {
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            let _injector = lock(&self.injector);
+            self.available.notify_one();
+        }
+    }
+
+    // Gives back the slot of a job that has left the queue
+    fn release(&self) {
+        self.len.fetch_sub(1, Ordering::SeqCst);
+
+        if self.blocked.load(Ordering::SeqCst) > 0 {
+            let _injector = lock(&self.injector);
+            self.space.notify_all();
+        }
+    }
+
+    // Finds a job without blocking: first urgent jobs in the injector, then our own deque,
+    // then the rest of the injector, then other workers' deques
+    fn find_job(&self, local: Option<&Arc<LocalQueue>>) -> Option<Queued> {
+        if self.urgent.load(Ordering::SeqCst) {
+            if let Some(job) = lock(&self.injector).pop(&self.urgent) {
+                return Some(job);
+            }
+        }
+
+        if let Some(job) = local.and_then(|local| lock(&local.jobs).pop_front()) {
+            return Some(job);
+        }
+
+        let locals = self.locals();
+
+        {
+            let mut injector = lock(&self.injector);
+
+            if let Some(job) = injector.pop(&self.urgent) {
+                // Take a share of what's left so we don't have to come back to the injector for every job.
+                // Urgent jobs are left where they are so any worker can pick them up straight away.
+                if let Some(local) = local {
+                    let batch = (injector.jobs.len() / locals.len().max(1)).min(MAX_BATCH);
+                    let mut local = lock(&local.jobs);
+
+                    for _ in 0..batch {
+                        if injector.has_urgent() {
+                            break;
+                        }
+                        match injector.pop(&self.urgent) {
+                            Some(job) => local.push_back(job),
+                            None => break,
+                        }
+                    }
+                }
+
+                return Some(job);
+            }
+        }
+
+        for victim in &locals {
+            if local.is_some_and(|local| Arc::ptr_eq(local, victim)) {
+                continue;
+            }
+
+            let mut victim = lock(&victim.jobs);
+
+            if let Some(job) = victim.pop_front() {
+                // Steal half of the rest too, the victim clearly has more than it can get through
+                if let Some(local) = local {
+                    let batch = (victim.len() / 2).min(MAX_BATCH);
+                    let stolen: Vec<Queued> = victim.drain(..batch).collect();
+                    drop(victim);
+                    lock(&local.jobs).extend(stolen);
+                }
+
+                return Some(job);
+            }
+        }
+
+        None
+    }
+
+    pub(crate) fn pop(&self, timeout: Option<Duration>) -> Pop {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let local = self.current_local();
+
+        loop {
+            if let Some(job) = self.find_job(local.as_ref()) {
+                self.release();
+                return Pop::Job(job);
+            }
+
+            let mut injector = lock(&self.injector);
+            self.sleepers.fetch_add(1, Ordering::SeqCst);
+
+            // A job may have been pushed since we looked. len also counts jobs that are about to be pushed,
+            // in which case we go round again until it shows up.
+            let pop = if self.len.load(Ordering::SeqCst) > 0 {
+                None
+            } else if injector.closed {
+                Some(Pop::Closed)
+            } else {
+                match deadline {
+                    None => {
+                        injector = self.available.wait(injector).unwrap_or_else(PoisonError::into_inner);
+                        None
+                    }
+                    Some(deadline) => {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            Some(Pop::TimedOut)
+                        } else {
+                            injector = self
+                                .available
+                                .wait_timeout(injector, deadline - now)
+                                .unwrap_or_else(PoisonError::into_inner)
+                                .0;
+                            None
+                        }
+                    }
+                }
+            };
+
+            self.sleepers.fetch_sub(1, Ordering::SeqCst);
+            drop(injector);
+
+            if let Some(pop) = pop {
+                return pop;
+            }
+        }
+    }
+
+    pub(crate) fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        lock(&self.injector).closed = true;
+
+        // wake everyone up so that blocked submitters give up and idle workers exit
+        self.available.notify_all();
+        self.space.notify_all();
+    }
+
+    // Takes every job out of the injector and all the deques
+    pub(crate) fn drain(&self) -> Vec<Job> {
+        let mut jobs = {
+            let mut injector = lock(&self.injector);
+            let jobs = injector.jobs.drain();
+            self.urgent.store(false, Ordering::SeqCst);
+            jobs
+        };
+
+        for local in self.locals() {
+            jobs.extend(lock(&local.jobs).drain(..));
+        }
+
+        self.len.fetch_sub(jobs.len(), Ordering::SeqCst);
+        self.space.notify_all();
+
+        jobs.into_iter().map(|queued| queued.job).collect()
+    }
+
+    // Takes the jobs whose token has been cancelled out of the injector and all the deques, and drops them.
+    // Returns how many were dropped.
+    pub(crate) fn remove_cancelled(&self) -> usize {
+        let mut removed = {
+            let mut injector = lock(&self.injector);
+            let removed = injector.jobs.remove_cancelled();
+            self.urgent.store(injector.has_urgent(), Ordering::SeqCst);
+            removed
+        };
+
+        for local in self.locals() {
+            removed.extend(queue::take_cancelled(&mut lock(&local.jobs)));
+        }
+
+        if !removed.is_empty() {
+            self.len.fetch_sub(removed.len(), Ordering::SeqCst);
+
+            let _injector = lock(&self.injector);
+            self.space.notify_all();
+        }
+
+        removed.len()
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn is_backed_up(&self) -> bool {
+        self.len.load(Ordering::SeqCst) > self.sleepers.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        CURRENT.with(|current| *current.borrow_mut() = None);
+
+        self.queue
+            .locals
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|local| !Arc::ptr_eq(local, &self.local));
+
+        // Hand anything left in our deque back to the injector so another worker picks it up
+        let leftover: Vec<Queued> = lock(&self.local.jobs).drain(..).collect();
+        if !leftover.is_empty() {
+            let mut injector = lock(&self.queue.injector);
+            for queued in leftover {
+                injector.push(Priority::NORMAL, queued, &self.queue.urgent);
+            }
+            drop(injector);
+            self.queue.available.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc, Arc, Mutex,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::StealingQueue;
+    use crate::{
+        queue::{JobInfo, Pop},
+        Backend, OverflowPolicy, PoolHandle, Priority, ThreadPool,
+    };
+
+    const AGING: Duration = Duration::from_secs(60);
+
+    fn queue(capacity: Option<usize>, policy: OverflowPolicy) -> StealingQueue {
+        StealingQueue::new(capacity, policy, AGING)
+    }
+
+    // Pushes a job that records `id` when it runs
+    fn push(queue: &StealingQueue, priority: Priority, ran: &Arc<Mutex<Vec<usize>>>, id: usize) {
+        let ran = Arc::clone(ran);
+        assert!(queue.push(move || ran.lock().unwrap().push(id), priority, JobInfo::default()).is_ok());
+    }
+
+    // Pops and runs one job without waiting
+    fn run_one(queue: &StealingQueue) -> bool {
+        match queue.pop(Some(Duration::ZERO)) {
+            Pop::Job(queued) => {
+                (queued.job)();
+                true
+            }
+            Pop::TimedOut | Pop::Closed => false,
+        }
+    }
+
+    // Submits a tree of jobs, most of them from inside other jobs, so they go through the workers' own deques
+    fn spawn_tree(handle: PoolHandle, runs: Arc<Vec<AtomicUsize>>, id: usize) {
+        runs[id].fetch_add(1, Ordering::SeqCst);
+
+        for child in [id * 2 + 1, id * 2 + 2] {
+            if child < runs.len() {
+                let (inner, runs) = (handle.clone(), Arc::clone(&runs));
+                handle.execute(move || spawn_tree(inner, runs, child));
+            }
+        }
+    }
+
+    #[test]
+    fn every_job_runs_exactly_once() {
+        let pool = ThreadPool::builder().num_threads(8).backend(Backend::WorkStealing).build().unwrap();
+        let runs: Arc<Vec<AtomicUsize>> = Arc::new((0..10_000).map(|_| AtomicUsize::new(0)).collect());
+
+        let (handle, tree) = (pool.handle(), Arc::clone(&runs));
+        pool.execute(move || spawn_tree(handle, tree, 0));
+
+        assert!(pool.wait_idle_timeout(Duration::from_secs(10)));
+        assert!(runs.iter().all(|runs| runs.load(Ordering::SeqCst) == 1));
+        assert_eq!(pool.queue_len(), 0);
+    }
+
+    #[test]
+    fn jobs_still_run_after_workers_retire() {
+        let pool = ThreadPool::builder()
+            .min_threads(0)
+            .max_threads(4)
+            .keep_alive(Duration::from_millis(20))
+            .backend(Backend::WorkStealing)
+            .build()
+            .unwrap();
+
+        for _ in 0..3 {
+            let runs: Arc<Vec<AtomicUsize>> = Arc::new((0..1_000).map(|_| AtomicUsize::new(0)).collect());
+            let (handle, tree) = (pool.handle(), Arc::clone(&runs));
+            pool.execute(move || spawn_tree(handle, tree, 0));
+
+            assert!(pool.wait_idle_timeout(Duration::from_secs(10)));
+            assert!(runs.iter().all(|runs| runs.load(Ordering::SeqCst) == 1));
+
+            // Let every worker retire before the next round
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while pool.worker_count() > 0 && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(5));
+            }
+            assert_eq!(pool.worker_count(), 0);
+        }
+    }
+
+    #[test]
+    fn dropping_a_registration_hands_its_jobs_back() {
+        let queue = queue(None, OverflowPolicy::Block);
+        let ran = Arc::new(Mutex::new(Vec::new()));
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                let registration = queue.register_worker();
+                for id in 0..5 {
+                    push(&queue, Priority::NORMAL, &ran, id);
+                }
+                drop(registration);
+            });
+        });
+
+        assert_eq!(queue.len(), 5);
+        while run_one(&queue) {}
+
+        assert_eq!(*ran.lock().unwrap(), [0, 1, 2, 3, 4]);
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn urgent_jobs_jump_the_deques() {
+        let queue = queue(None, OverflowPolicy::Block);
+        let ran = Arc::new(Mutex::new(Vec::new()));
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                let _registration = queue.register_worker();
+
+                // These go on the worker's own deque, the high priority one into the injector
+                for id in 0..3 {
+                    push(&queue, Priority::NORMAL, &ran, id);
+                }
+                push(&queue, Priority::HIGH, &ran, 3);
+
+                while run_one(&queue) {}
+            });
+        });
+
+        assert_eq!(*ran.lock().unwrap(), [3, 0, 1, 2]);
+    }
+
+    #[test]
+    fn blocked_push_keeps_len_within_capacity() {
+        let queue = queue(Some(2), OverflowPolicy::Block);
+        let ran = Arc::new(Mutex::new(Vec::new()));
+
+        push(&queue, Priority::NORMAL, &ran, 0);
+        push(&queue, Priority::NORMAL, &ran, 1);
+        assert_eq!(queue.len(), 2);
+
+        thread::scope(|s| {
+            let (pushed, done) = mpsc::channel();
+            let (queue, ran) = (&queue, &ran);
+            let blocked = s.spawn(move || {
+                push(queue, Priority::NORMAL, ran, 2);
+                pushed.send(()).unwrap();
+            });
+
+            // The third push waits for room
+            assert!(done.recv_timeout(Duration::from_millis(50)).is_err());
+            assert_eq!(queue.len(), 2);
+
+            assert!(run_one(queue));
+            done.recv_timeout(Duration::from_secs(5)).unwrap();
+            blocked.join().unwrap();
+        });
+
+        assert_eq!(queue.len(), 2);
+        while run_one(&queue) {}
+        assert_eq!(queue.len(), 0);
+        assert_eq!(*ran.lock().unwrap(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn drop_oldest_keeps_len_at_capacity() {
+        let queue = queue(Some(2), OverflowPolicy::DropOldest);
+        let ran = Arc::new(Mutex::new(Vec::new()));
+
+        for id in 0..5 {
+            push(&queue, Priority::NORMAL, &ran, id);
+            assert!(queue.len() <= 2);
+        }
+
+        assert_eq!(queue.len(), 2);
+        while run_one(&queue) {}
+        assert_eq!(queue.len(), 0);
+        assert_eq!(*ran.lock().unwrap(), [3, 4]);
+    }
+
+    #[test]
+    fn drain_and_close_empty_the_queue() {
+        let queue = queue(None, OverflowPolicy::Block);
+        let ran = Arc::new(Mutex::new(Vec::new()));
+
+        for id in 0..4 {
+            push(&queue, Priority::NORMAL, &ran, id);
+        }
+
+        queue.close();
+        assert_eq!(queue.drain().len(), 4);
+        assert_eq!(queue.len(), 0);
+        assert!(matches!(queue.pop(None), Pop::Closed));
+    }
+}
@@ -0,0 +1,233 @@
+use std::{
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    pin::{pin, Pin},
+    sync::{
+        atomic::{AtomicBool, AtomicU8, Ordering},
+        Arc, Mutex, Weak,
+    },
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, Thread},
+};
+
+use crate::{handle::Completer, lock, queue::JobInfo, Priority, Shared};
+
+// A task moves between these states as it is woken and polled. Only the move from IDLE to SCHEDULED submits a job,
+// so however often a task is woken there is never more than one job for it in the queue.
+const IDLE: u8 = 0;
+const SCHEDULED: u8 = 1;
+const RUNNING: u8 = 2;
+// Woken while it was being polled, so it needs polling again once the current poll is over
+const NOTIFIED: u8 = 3;
+const DONE: u8 = 4;
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+// A future running on the pool. Each poll is a job, and waking the task submits the next one.
+pub(crate) struct Task {
+    future: Mutex<Option<BoxFuture>>,
+    state: AtomicU8,
+    // Weak so a task that is never woken again doesn't keep the pool's internals alive
+    shared: Weak<Shared>,
+}
+
+impl Task {
+    pub(crate) fn spawn<F>(shared: &Arc<Shared>, future: F, completer: Completer<F::Output>)
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let task = Arc::new(Task {
+            future: Mutex::new(Some(Box::pin(Completing { future: Box::pin(future), completer: Some(completer) }))),
+            state: AtomicU8::new(SCHEDULED),
+            shared: Arc::downgrade(shared),
+        });
+
+        // The first poll is submitted like any other job, so it follows the pool's overflow policy
+        let first = Arc::clone(&task);
+        if shared.try_execute(Priority::NORMAL, move || first.run()).is_err() {
+            task.cancel();
+        }
+    }
+
+    fn run(self: Arc<Self>) {
+        self.state.store(RUNNING, Ordering::SeqCst);
+
+        let waker = Waker::from(Arc::clone(&self));
+        let mut cx = Context::from_waker(&waker);
+
+        let finished = {
+            let mut future = lock(&self.future);
+            let ready = future.as_mut().is_some_and(|running| running.as_mut().poll(&mut cx).is_ready());
+            if ready {
+                future.take()
+            } else {
+                None
+            }
+        };
+
+        if finished.is_some() {
+            self.state.store(DONE, Ordering::SeqCst);
+            drop(finished);
+            return;
+        }
+
+        // If we were woken during the poll, go round again as a new job rather than polling in a loop here,
+        // so other jobs get a turn
+        if self.state.compare_exchange(RUNNING, IDLE, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            self.state.store(SCHEDULED, Ordering::SeqCst);
+            self.submit();
+        }
+    }
+
+    // Queues the next poll. Wakers can be called from anywhere, including workers, and must not block,
+    // so this goes through `Shared::requeue` rather than the overflow policy.
+    fn submit(self: &Arc<Self>) {
+        let task = Arc::clone(self);
+        let submitted = match self.shared.upgrade() {
+            Some(shared) => shared.requeue(JobInfo::default(), move || task.run()).is_ok(),
+            None => false,
+        };
+
+        if !submitted {
+            self.cancel();
+        }
+    }
+
+    // The pool won't take the task, so it will never be polled again. Dropping the future resolves its handle as cancelled.
+    fn cancel(&self) {
+        self.state.store(DONE, Ordering::SeqCst);
+        let future = lock(&self.future).take();
+        drop(future);
+    }
+}
+
+impl Wake for Task {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        let mut state = self.state.load(Ordering::SeqCst);
+
+        loop {
+            let next = match state {
+                IDLE => SCHEDULED,
+                RUNNING => NOTIFIED,
+                // Already queued, already due another poll, or finished
+                _ => return,
+            };
+
+            match self.state.compare_exchange(state, next, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => {
+                    if next == SCHEDULED {
+                        self.submit();
+                    }
+                    return;
+                }
+                Err(current) => state = current,
+            }
+        }
+    }
+}
+
+// Wraps the spawned future to hand its output, or its panic, to the JobHandle
+struct Completing<F: Future> {
+    // Boxed so that Completing is Unpin whatever the future is, which saves us from pin projections
+    future: Pin<Box<F>>,
+    completer: Option<Completer<F::Output>>,
+}
+
+impl<F: Future> Future for Completing<F> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = &mut *self;
+
+        // A future that panicked is never polled again, it is dropped along with this task
+        let result = match panic::catch_unwind(AssertUnwindSafe(|| this.future.as_mut().poll(cx))) {
+            Ok(Poll::Pending) => return Poll::Pending,
+            Ok(Poll::Ready(output)) => Ok(output),
+            Err(payload) => Err(payload),
+        };
+
+        if let Some(completer) = this.completer.take() {
+            completer.complete(result);
+        }
+
+        Poll::Ready(())
+    }
+}
+
+// Wakes a thread blocked in block_on
+struct ThreadWaker {
+    thread: Thread,
+    woken: AtomicBool,
+}
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::SeqCst);
+        self.thread.unpark();
+    }
+}
+
+/// Run a future to completion on the current thread, blocking until it is done.
+///
+/// This is the simplest possible executor: it polls the future, and parks the thread until the future is woken.
+/// Calling it from one of the pool's jobs ties up that worker until the future is done.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let thread_waker = Arc::new(ThreadWaker { thread: thread::current(), woken: AtomicBool::new(false) });
+    let waker = Waker::from(Arc::clone(&thread_waker));
+    let mut cx = Context::from_waker(&waker);
+
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+
+        // park() can return without being unparked, so keep going until the future has actually been woken
+        while !thread_waker.woken.swap(false, Ordering::SeqCst) {
+            thread::park();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{future, task::Poll, time::Duration};
+
+    use crate::{OverflowPolicy, ThreadPool};
+
+    #[test]
+    fn future_woken_on_a_worker_with_a_full_queue_is_polled_again() {
+        let pool = ThreadPool::builder()
+            .num_threads(1)
+            .queue_capacity(1)
+            .overflow_policy(OverflowPolicy::Block)
+            .build()
+            .unwrap();
+        let handle = pool.handle();
+        let mut polls = 0;
+
+        // The first poll fills the queue and then wakes the task, so its next poll has no room to go in
+        let task = pool.spawn_future(future::poll_fn(move |cx| {
+            polls += 1;
+            if polls == 1 {
+                handle.execute(|| {});
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            } else {
+                Poll::Ready(polls)
+            }
+        }));
+
+        assert!(pool.wait_idle_timeout(Duration::from_secs(5)));
+        assert_eq!(task.join().unwrap(), 2);
+    }
+}
@@ -0,0 +1,395 @@
+use std::{
+    cmp::Ordering as CmpOrdering,
+    collections::BinaryHeap,
+    fmt, io, mem,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{lock, Job, Priority, Shared};
+
+/// A handle to a job scheduled with `ThreadPool::schedule_after` or `ThreadPool::schedule_every`.
+///
+/// Dropping the handle does not cancel the job, call [`ScheduleHandle::cancel`] for that.
+#[derive(Debug, Clone)]
+pub struct ScheduleHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl ScheduleHandle {
+    /// Stop the job from being run again.
+    ///
+    /// A run that has already been handed to the workers is not interrupted.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns `true` if the job has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+// Why a job couldn't be scheduled
+pub(crate) enum ScheduleError {
+    // The pool is shutting down, so the timer won't run anything again
+    ShuttingDown,
+    // The timer thread couldn't be spawned
+    Spawn(io::Error),
+}
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScheduleError::ShuttingDown => f.write_str("thread pool is shutting down"),
+            ScheduleError::Spawn(e) => write!(f, "failed to start timer thread: {e}"),
+        }
+    }
+}
+
+enum Task {
+    Once(Job),
+    Every {
+        f: Arc<dyn Fn() + Send + Sync + 'static>,
+        period: Duration,
+        // Set while a run is queued or running, so a slow job doesn't pile up runs behind it
+        running: Arc<AtomicBool>,
+    },
+}
+
+struct Entry {
+    due: Instant,
+    // Breaks ties between entries due at the same time so they fire in the order they were scheduled
+    seq: u64,
+    task: Task,
+    cancelled: Arc<AtomicBool>,
+}
+
+// BinaryHeap is a max-heap, so the ordering is reversed to get the entry that is due soonest at the top
+impl Ord for Entry {
+    fn cmp(&self, other: &Entry) -> CmpOrdering {
+        (other.due, other.seq).cmp(&(self.due, self.seq))
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Entry) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Entry) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+struct TimerState {
+    entries: BinaryHeap<Entry>,
+    next_seq: u64,
+    shutdown: bool,
+}
+
+// A single thread that sleeps until the next scheduled job is due and then hands it to the workers.
+// The thread is only started the first time something is scheduled.
+pub(crate) struct Timer {
+    state: Mutex<TimerState>,
+    // Signalled when an entry is added or the timer is shut down
+    changed: Condvar,
+    thread: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+// Clears a periodic job's `running` flag once the run is over, including when it panics or is never run at all
+struct RunningGuard(Arc<AtomicBool>);
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Timer {
+    pub(crate) fn new() -> Timer {
+        Timer {
+            state: Mutex::new(TimerState {
+                entries: BinaryHeap::new(),
+                next_seq: 0,
+                shutdown: false,
+            }),
+            changed: Condvar::new(),
+            thread: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn schedule_once(
+        &self,
+        shared: &Arc<Shared>,
+        delay: Duration,
+        job: Job,
+    ) -> Result<ScheduleHandle, ScheduleError> {
+        self.schedule(shared, delay, Task::Once(job))
+    }
+
+    pub(crate) fn schedule_every(
+        &self,
+        shared: &Arc<Shared>,
+        period: Duration,
+        f: Arc<dyn Fn() + Send + Sync + 'static>,
+    ) -> Result<ScheduleHandle, ScheduleError> {
+        let running = Arc::new(AtomicBool::new(false));
+        self.schedule(shared, period, Task::Every { f, period, running })
+    }
+
+    fn schedule(&self, shared: &Arc<Shared>, delay: Duration, task: Task) -> Result<ScheduleHandle, ScheduleError> {
+        self.start(shared)?;
+
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let mut state = lock(&self.state);
+
+        // The timer may have been shut down since we started it, and then nothing would ever run the job
+        if state.shutdown {
+            return Err(ScheduleError::ShuttingDown);
+        }
+
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.entries.push(Entry {
+            due: Instant::now() + delay,
+            seq,
+            task,
+            cancelled: Arc::clone(&cancelled),
+        });
+
+        drop(state);
+        // The new entry may be due before whatever the timer thread is currently sleeping until
+        self.changed.notify_one();
+
+        Ok(ScheduleHandle { cancelled })
+    }
+
+    // Starts the timer thread if it isn't running yet, unless it has been shut down for good
+    fn start(&self, shared: &Arc<Shared>) -> Result<(), ScheduleError> {
+        let mut thread = lock(&self.thread);
+
+        if lock(&self.state).shutdown {
+            return Err(ScheduleError::ShuttingDown);
+        }
+
+        if thread.is_none() {
+            let shared = Arc::clone(shared);
+            let spawned = thread::Builder::new().spawn(move || shared.timer.run(&shared));
+            *thread = Some(spawned.map_err(ScheduleError::Spawn)?);
+        }
+
+        Ok(())
+    }
+
+    fn run(&self, shared: &Arc<Shared>) {
+        let mut state = lock(&self.state);
+
+        loop {
+            if state.shutdown {
+                return;
+            }
+
+            let now = Instant::now();
+            let next_due = state.entries.peek().map(|entry| entry.due);
+
+            match next_due {
+                None => {
+                    state = self.changed.wait(state).unwrap_or_else(PoisonError::into_inner);
+                }
+                Some(due) if due > now => {
+                    state = self
+                        .changed
+                        .wait_timeout(state, due - now)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0;
+                }
+                Some(_) => {
+                    let entry = state.entries.pop().unwrap();
+
+                    // Submitting can block if the queue is full, so don't hold the lock while we do it
+                    drop(state);
+                    let next = fire(shared, entry, now);
+                    state = lock(&self.state);
+
+                    if let Some(next) = next {
+                        state.entries.push(next);
+                    }
+                }
+            }
+        }
+    }
+
+    // Stops the timer thread. Anything still scheduled is dropped without running.
+    pub(crate) fn shutdown(&self) {
+        let mut state = lock(&self.state);
+        state.shutdown = true;
+        let entries = mem::take(&mut state.entries);
+        drop(state);
+        self.changed.notify_all();
+        drop(entries);
+
+        let thread = lock(&self.thread).take();
+        if let Some(thread) = thread {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Hands a due entry to the workers, returning the entry again if it is periodic
+fn fire(shared: &Arc<Shared>, entry: Entry, now: Instant) -> Option<Entry> {
+    if entry.cancelled.load(Ordering::SeqCst) {
+        return None;
+    }
+
+    match entry.task {
+        Task::Once(job) => {
+            // If the pool rejects the job there is nobody to hand it back to, so it is dropped
+            let _ = shared.try_execute(Priority::NORMAL, job);
+            None
+        }
+        Task::Every { f, period, running } => {
+            // Skip this run if the last one hasn't finished yet
+            if !running.swap(true, Ordering::SeqCst) {
+                let guard = RunningGuard(Arc::clone(&running));
+                let run = Arc::clone(&f);
+
+                let _ = shared.try_execute(Priority::NORMAL, move || {
+                    let _guard = guard;
+                    run();
+                });
+            }
+
+            // Runs stay on the original schedule, unless we've fallen behind in which case we start again from now
+            let mut due = entry.due + period;
+            if due <= now {
+                due = now + period;
+            }
+
+            Some(Entry {
+                due,
+                task: Task::Every { f, period, running },
+                ..entry
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc, Arc, Mutex,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::ThreadPool;
+
+    // Polls `count` until it reaches `target`, giving up after five seconds
+    fn wait_for(count: &AtomicUsize, target: usize) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while count.load(Ordering::SeqCst) < target {
+            if Instant::now() > deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        true
+    }
+
+    #[test]
+    fn schedule_after_runs_once_the_delay_has_passed() {
+        let pool = ThreadPool::new(1);
+        let (ran, runs) = mpsc::channel();
+        let start = Instant::now();
+
+        let handle = pool.schedule_after(Duration::from_millis(20), move || ran.send(Instant::now()).unwrap());
+
+        let ran_at = runs.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(ran_at - start >= Duration::from_millis(20));
+        assert!(!handle.is_cancelled());
+        assert!(runs.recv_timeout(Duration::from_millis(50)).is_err());
+    }
+
+    #[test]
+    fn schedule_every_runs_until_cancelled() {
+        let pool = ThreadPool::new(1);
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&runs);
+
+        let handle = pool.schedule_every(Duration::from_millis(5), move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+        });
+        assert!(wait_for(&runs, 3));
+
+        handle.cancel();
+        assert!(handle.is_cancelled());
+
+        // A run already handed to the workers may still finish, but nothing is started after that
+        thread::sleep(Duration::from_millis(20));
+        let after_cancel = runs.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(runs.load(Ordering::SeqCst), after_cancel);
+    }
+
+    #[test]
+    fn cancelled_job_never_runs() {
+        let pool = ThreadPool::new(1);
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&runs);
+
+        let handle = pool.schedule_after(Duration::from_millis(20), move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+        });
+        handle.cancel();
+
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(runs.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn periodic_run_is_skipped_while_the_last_one_is_still_running() {
+        // Two workers, so an overlapping run would have a worker free to start it
+        let pool = ThreadPool::new(2);
+        let runs = Arc::new(AtomicUsize::new(0));
+        let (release, blocked) = mpsc::channel::<()>();
+        let blocked = Mutex::new(blocked);
+        let counted = Arc::clone(&runs);
+
+        let handle = pool.schedule_every(Duration::from_millis(5), move || {
+            // Only the first run blocks, until it is released
+            if counted.fetch_add(1, Ordering::SeqCst) == 0 {
+                let _ = blocked.lock().unwrap().recv();
+            }
+        });
+        assert!(wait_for(&runs, 1));
+
+        // Ten periods go by while the first run is still going
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+        release.send(()).unwrap();
+        assert!(wait_for(&runs, 2));
+        handle.cancel();
+    }
+
+    #[test]
+    #[should_panic(expected = "shutting down")]
+    fn scheduling_after_shutdown_panics() {
+        let pool = ThreadPool::new(1);
+        pool.shutdown(Duration::from_secs(1));
+
+        pool.schedule_after(Duration::from_millis(1), || {});
+    }
+}
@@ -0,0 +1,262 @@
+use std::{
+    io,
+    sync::{atomic::Ordering, Arc, Condvar, Mutex, PoisonError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{lock, logging::event, queue::JobInfo, Shared};
+
+// How often the watchdog looks at the running jobs, so a job is flagged within this long of going over budget
+const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A job that has been running for longer than its time budget, as reported by the watchdog.
+///
+/// See `ThreadPoolBuilder::job_budget` and `ThreadPool::execute_with_budget`.
+#[derive(Debug, Clone)]
+pub struct StuckJob {
+    /// The id of the worker running the job.
+    pub worker_id: usize,
+    /// The label the job was submitted with, if any.
+    pub label: Option<String>,
+    /// How long the job had been running when the watchdog noticed.
+    pub elapsed: Duration,
+    /// The budget the job went over.
+    pub budget: Duration,
+}
+
+// The job a worker is running, as seen by the watchdog
+struct RunningJob {
+    started: Instant,
+    label: Option<Arc<str>>,
+    budget: Option<Duration>,
+    // Each job is only reported once, however long it keeps running
+    reported: bool,
+}
+
+struct ActivityState {
+    current: Option<RunningJob>,
+    // Set when the watchdog has started a replacement for the worker, which then exits once its job is over
+    abandoned: bool,
+}
+
+// What a worker is up to, shared between the worker thread, the pool and the watchdog
+pub(crate) struct Activity {
+    state: Mutex<ActivityState>,
+}
+
+impl Activity {
+    pub(crate) fn new() -> Activity {
+        Activity {
+            state: Mutex::new(ActivityState { current: None, abandoned: false }),
+        }
+    }
+
+    pub(crate) fn start(&self, info: &JobInfo) {
+        lock(&self.state).current = Some(RunningJob {
+            started: Instant::now(),
+            label: info.label.clone(),
+            budget: info.budget,
+            reported: false,
+        });
+    }
+
+    // Returns true if the worker has been replaced while it was running the job, in which case it should exit
+    pub(crate) fn finish(&self) -> bool {
+        let mut state = lock(&self.state);
+        state.current = None;
+        state.abandoned
+    }
+
+    pub(crate) fn is_abandoned(&self) -> bool {
+        lock(&self.state).abandoned
+    }
+
+    // Returns the running job if it has gone over budget and hasn't been reported yet
+    fn check(&self, worker_id: usize, default_budget: Option<Duration>) -> Option<StuckJob> {
+        let mut state = lock(&self.state);
+        let job = state.current.as_mut()?;
+        let budget = job.budget.or(default_budget)?;
+        let elapsed = job.started.elapsed();
+
+        if job.reported || elapsed <= budget {
+            return None;
+        }
+
+        job.reported = true;
+
+        Some(StuckJob {
+            worker_id,
+            label: job.label.as_deref().map(str::to_owned),
+            elapsed,
+            budget,
+        })
+    }
+
+    // Marks the worker as replaced, as long as it is still running a job. Returns false if it already finished.
+    fn abandon(&self) -> bool {
+        let mut state = lock(&self.state);
+
+        if state.current.is_none() || state.abandoned {
+            return false;
+        }
+
+        state.abandoned = true;
+        true
+    }
+}
+
+// A thread that wakes up every CHECK_INTERVAL to look for jobs that have been running for too long.
+// Like the timer, the thread is only started once something needs it.
+pub(crate) struct Watchdog {
+    shutdown: Mutex<bool>,
+    // Signalled when the watchdog is shut down
+    changed: Condvar,
+    thread: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl Watchdog {
+    pub(crate) fn new() -> Watchdog {
+        Watchdog {
+            shutdown: Mutex::new(false),
+            changed: Condvar::new(),
+            thread: Mutex::new(None),
+        }
+    }
+
+    // Starts the watchdog thread if it isn't running yet
+    pub(crate) fn start(&self, shared: &Arc<Shared>) -> io::Result<()> {
+        let mut thread = lock(&self.thread);
+
+        if thread.is_none() {
+            let shared = Arc::clone(shared);
+            *thread = Some(thread::Builder::new().spawn(move || shared.watchdog.run(&shared))?);
+        }
+
+        Ok(())
+    }
+
+    fn run(&self, shared: &Arc<Shared>) {
+        let mut shutdown = lock(&self.shutdown);
+
+        while !*shutdown {
+            shutdown = self
+                .changed
+                .wait_timeout(shutdown, CHECK_INTERVAL)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+
+            if !*shutdown {
+                // The handler and replacement workers shouldn't hold up shutting down, so don't hold the lock while we check
+                drop(shutdown);
+                check(shared);
+                shutdown = lock(&self.shutdown);
+            }
+        }
+    }
+
+    pub(crate) fn shutdown(&self) {
+        *lock(&self.shutdown) = true;
+        self.changed.notify_all();
+
+        let thread = lock(&self.thread).take();
+        if let Some(thread) = thread {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Reports every job that has gone over budget since the last check, and replaces their workers if the pool asks for it
+fn check(shared: &Arc<Shared>) {
+    let stuck: Vec<_> = lock(&shared.workers)
+        .iter()
+        .filter_map(|worker| {
+            let job = worker.activity.check(worker.id, shared.job_budget)?;
+            Some((job, Arc::clone(&worker.activity)))
+        })
+        .collect();
+
+    for (job, activity) in stuck {
+        match &shared.stuck_job_handler {
+            Some(handler) => handler(&job),
+            None => event!(
+                warn,
+                worker = job.worker_id,
+                elapsed_ms = job.elapsed.as_millis() as u64,
+                budget_ms = job.budget.as_millis() as u64;
+                "{} has been running for {:?}, over its budget of {:?}",
+                job.label.as_deref().unwrap_or("job"),
+                job.elapsed,
+                job.budget
+            ),
+        }
+
+        if shared.replace_stuck_workers && !shared.queue.is_closed() && activity.abandon() {
+            // The stuck worker gives up its slot straight away so the replacement can take it.
+            // It exits once its job is over without touching live_workers again.
+            shared.live_workers.fetch_sub(1, Ordering::SeqCst);
+            event!(warn, worker = job.worker_id; "worker is stuck; starting a replacement");
+
+            // If this fails the pool is a worker down until the stuck one finishes, as it would have been without us
+            let _ = shared.add_worker();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, time::Duration};
+
+    use crate::ThreadPool;
+
+    #[test]
+    fn stuck_job_is_reported_once_with_its_label_and_budget() {
+        let (reported, reports) = mpsc::channel();
+        let pool = ThreadPool::builder()
+            .num_threads(1)
+            .on_stuck_job(move |job| reported.send(job.clone()).unwrap())
+            .build()
+            .unwrap();
+        let (release, blocked) = mpsc::channel::<()>();
+
+        pool.execute_with_budget("slow job", Duration::from_millis(20), move || {
+            let _ = blocked.recv();
+        });
+
+        let job = reports.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(job.worker_id, 0);
+        assert_eq!(job.label.as_deref(), Some("slow job"));
+        assert_eq!(job.budget, Duration::from_millis(20));
+        assert!(job.elapsed > job.budget);
+
+        // The watchdog checks several more times while the job keeps running, without reporting it again
+        assert!(reports.recv_timeout(Duration::from_millis(350)).is_err());
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn stuck_worker_is_replaced_without_shrinking_the_pool() {
+        let (reported, reports) = mpsc::channel();
+        let pool = ThreadPool::builder()
+            .num_threads(1)
+            .job_budget(Duration::from_millis(20))
+            .replace_stuck_workers(true)
+            .on_stuck_job(move |job| reported.send(job.label.clone()).unwrap())
+            .build()
+            .unwrap();
+        let (release, blocked) = mpsc::channel::<()>();
+
+        pool.execute(move || {
+            let _ = blocked.recv();
+        });
+        assert_eq!(reports.recv_timeout(Duration::from_secs(5)), Ok(None));
+
+        // The replacement runs jobs while the stuck worker is still blocked
+        let (done, finished) = mpsc::channel();
+        pool.execute(move || done.send(()).unwrap());
+        assert!(finished.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert_eq!(pool.worker_count(), 1);
+
+        release.send(()).unwrap();
+    }
+}